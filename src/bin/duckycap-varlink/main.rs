@@ -0,0 +1,87 @@
+//! Ducky Relay Varlink Service
+//!
+//! A varlink service that listens for keystroke messages and executes
+//! configured commands as a specific user based on a TOML config file. The
+//! service itself lives in [`ducky_relay::service`].
+
+use clap::Parser;
+use ducky_relay::service::{ServerConfig, check, run_server};
+use ducky_relay::{Redact, init_logging};
+use std::path::PathBuf;
+use tracing::info;
+
+/// `DuckyPad` varlink service - executes commands based on key combinations
+#[derive(Parser)]
+#[command(name = "duckycap-varlink")]
+struct Args {
+    /// Path to TOML configuration file
+    #[arg(short, long)]
+    config: PathBuf,
+
+    /// Socket to bind when not started through systemd socket activation.
+    /// Defaults to /run/duckycap.varlink for root and to
+    /// `$XDG_RUNTIME_DIR/duckycap.varlink` for other users
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    /// Log filter such as `debug` or `duckycap_varlink=trace`, overriding `RUST_LOG`
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Log to the systemd journal with structured fields instead of stderr
+    #[arg(long)]
+    journald: bool,
+
+    /// Check the config file for problems and exit, non-zero if there are any
+    #[arg(long)]
+    check: bool,
+
+    /// Leave key names out of logs. `auto` redacts them unless the event
+    /// comes from a device the capture daemon marks as a macro pad
+    #[arg(long, value_enum, default_value_t = Redact::Auto)]
+    redact: Redact,
+
+    /// Log and record the commands key events trigger without executing
+    /// them. Can be toggled at runtime with `SetDryRun`
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    if args.check {
+        let diagnostics = check::check(&args.config);
+        for diagnostic in &diagnostics {
+            println!(
+                "{}",
+                check::Report {
+                    path: &args.config,
+                    diagnostic,
+                }
+            );
+        }
+        if diagnostics.iter().any(|diagnostic| !diagnostic.note) {
+            std::process::exit(1);
+        }
+        println!("{}: OK", args.config.display());
+        return;
+    }
+
+    if let Err(e) = init_logging(args.log_level.as_deref(), args.journald) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+
+    info!("Starting ducky-relay varlink server");
+
+    let mut config = ServerConfig::load(args.config);
+    config.redact = args.redact;
+    config.dry_run = args.dry_run;
+    if let Some(socket) = args.socket {
+        config.socket = socket;
+    }
+
+    run_server(config).await;
+}
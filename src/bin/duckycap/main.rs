@@ -0,0 +1,411 @@
+//! `DuckyPad` Capture Daemon
+//!
+//! Captures input from duckyPad keyboard using evdev with exclusive grab,
+//! blocking input from reaching the system and forwarding key combinations
+//! to the varlink service.
+//!
+//! On SIGTERM or SIGINT the daemon sends release events for all held keys
+//! and releases the grab before exiting.
+//!
+//! Key names are only logged for devices known to be macro pads, so nothing
+//! typed on a regular keyboard ends up in the journal.
+//!
+//! `duckycap record <file>` also writes every input event to a trace, and
+//! `duckycap replay <file>` forwards the keys of a trace instead of a device,
+//! to reproduce the pad's behavior without the hardware.
+
+mod sink;
+mod source;
+mod trace;
+mod tracker;
+
+use clap::{Parser, Subcommand};
+use ducky_relay::{CaptureDevice, Redact, ShutdownSignals, default_socket, init_logging};
+use error_stack::{Report, ResultExt};
+use evdev::Device;
+use sink::{EventSink, StdoutSink, VarlinkSink};
+use source::{DeviceSource, InputSource};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::time::Instant;
+use trace::{TraceReader, TraceWriter};
+use tracing::{error, info, trace, warn};
+use tracker::KeyTracker;
+use wherror::Error;
+
+#[derive(Debug, Error)]
+#[error(debug)]
+pub struct DuckycapError;
+
+const DUCKYPAD_SYMLINK: &str = "/dev/input/duckypad";
+const DUCKYPAD_VENDOR_ID: u16 = 0x0483;
+const DUCKYPAD_PRODUCT_ID: u16 = 0xD11C;
+
+/// Default time to ignore bounces of a key after it changed state
+const DEFAULT_CHATTER_MS: u64 = 10;
+
+/// `DuckyPad` capture daemon - forwards key combinations to the varlink service
+#[derive(Parser)]
+#[command(name = "duckycap")]
+struct Args {
+    /// Time in milliseconds to ignore bounces of a key after it changed
+    /// state (0 disables the chatter filter)
+    #[arg(long, default_value_t = DEFAULT_CHATTER_MS)]
+    chatter_ms: u64,
+
+    /// Log filter such as `debug` or `duckycap=trace`, overriding `RUST_LOG`
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Log to the systemd journal with structured fields instead of stderr
+    #[arg(long)]
+    journald: bool,
+
+    /// Leave key names out of logs. `auto` redacts them unless the device
+    /// is a known macro pad
+    #[arg(long, value_enum, default_value_t = Redact::Auto)]
+    redact: Redact,
+
+    /// Print key events as JSON lines instead of sending them to the
+    /// varlink service
+    #[arg(long)]
+    stdout: bool,
+
+    /// Varlink socket of the service. Defaults to /run/duckycap.varlink for
+    /// root and to `$XDG_RUNTIME_DIR/duckycap.varlink` for other users
+    #[arg(long)]
+    socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Capture as usual, also writing every input event to a JSONL trace
+    Record {
+        /// Trace file to write, replaced if it exists
+        file: PathBuf,
+    },
+    /// Forward the keys of a recorded trace instead of capturing a device
+    Replay {
+        /// Trace file to read
+        file: PathBuf,
+    },
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+
+    if let Err(e) = init_logging(args.log_level.as_deref(), args.journald) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
+
+    if args.stdout {
+        run(args, &mut StdoutSink).await;
+    } else {
+        let socket = args.socket.clone().unwrap_or_else(default_socket);
+        run(args, &mut VarlinkSink::new(socket)).await;
+    }
+}
+
+/// Capture or replay as the command line says, exiting on errors
+async fn run(args: Args, sink: &mut impl EventSink) {
+    let chatter_window = Duration::from_millis(args.chatter_ms);
+
+    let record = match args.mode {
+        None => None,
+        Some(Mode::Record { file }) => Some(file),
+        Some(Mode::Replay { file }) => {
+            // Whether the trace came from a macro pad isn't known
+            let redact = args.redact.resolve(true);
+            if let Err(e) = run_replay(&file, chatter_window, redact, sink).await {
+                error!("Replay error: {e:?}");
+                std::process::exit(1);
+            }
+            return;
+        }
+    };
+
+    info!("Starting duckyPad capture daemon");
+
+    // Find and open the duckyPad device
+    let Some(device) = find_duckypad_device() else {
+        error!("duckyPad device not found. Exiting.");
+        std::process::exit(1);
+    };
+
+    info!(device = device.name().unwrap_or("unknown"), "Found device");
+
+    let redact = args.redact.resolve(!is_macro_pad(&device));
+    if redact {
+        info!("Key names are redacted from logs");
+    }
+
+    let trace = record.map(|path| match TraceWriter::create(&path) {
+        Ok(writer) => {
+            info!("Recording input events to {}", path.display());
+            if !is_macro_pad(&device) {
+                warn!("The trace will contain everything typed on this device");
+            }
+            writer
+        }
+        Err(e) => {
+            error!("{e:?}");
+            std::process::exit(1);
+        }
+    });
+
+    // Run the capture loop
+    if let Err(e) = run_capture(device, chatter_window, redact, trace, sink).await {
+        error!("Capture error: {e:?}");
+        std::process::exit(1);
+    }
+}
+
+/// Find the duckyPad device, preferring the udev symlink if available
+fn find_duckypad_device() -> Option<Device> {
+    // First, try the udev symlink
+    if Path::new(DUCKYPAD_SYMLINK).exists() {
+        if let Ok(device) = Device::open(DUCKYPAD_SYMLINK) {
+            // Verify it's the correct device
+            #[allow(clippy::redundant_else)]
+            if is_duckypad(&device) {
+                info!("Using device via udev symlink: {DUCKYPAD_SYMLINK}");
+                return Some(device);
+            } else {
+                warn!("Symlink exists but device doesn't match expected VID:PID");
+            }
+        }
+    }
+
+    // Fall back to scanning all devices
+    info!("Scanning for duckyPad device by VID:PID...");
+
+    for (_path, device) in evdev::enumerate() {
+        if is_duckypad(&device) {
+            info!("Found duckyPad at {:?}", device.physical_path());
+            return Some(device);
+        }
+    }
+
+    None
+}
+
+/// Check if a device matches the duckyPad VID:PID
+fn is_duckypad(device: &Device) -> bool {
+    let id = device.input_id();
+    id.vendor() == DUCKYPAD_VENDOR_ID && id.product() == DUCKYPAD_PRODUCT_ID
+}
+
+/// Check if a device is a macro pad, whose key names may be logged
+fn is_macro_pad(device: &Device) -> bool {
+    is_duckypad(device)
+}
+
+/// Identify a device by its VID:PID and physical path, if it has one
+fn device_id(device: &Device) -> String {
+    let id = device.input_id();
+    let vid_pid = format!("{:04x}:{:04x}", id.vendor(), id.product());
+    match device.physical_path() {
+        Some(phys) => format!("{vid_pid}@{phys}"),
+        None => vid_pid,
+    }
+}
+
+/// Grab the device and forward its keys, recording its events to `trace`
+async fn run_capture(
+    mut device: Device,
+    chatter_window: Duration,
+    redact: bool,
+    trace: Option<TraceWriter>,
+    sink: &mut impl EventSink,
+) -> Result<(), Report<DuckycapError>> {
+    // Grab the device exclusively - this blocks input from reaching other applications
+    device
+        .grab()
+        .change_context(DuckycapError)
+        .attach("failed to grab device")?;
+    info!("Device grabbed exclusively. Input will be blocked from the system.");
+
+    let tracker = KeyTracker::new(device_id(&device), chatter_window, redact);
+
+    sink.report_devices(&[CaptureDevice {
+        id: device_id(&device),
+        name: device.name().unwrap_or("unknown").to_string(),
+    }])
+    .await;
+
+    let events = device
+        .into_event_stream()
+        .change_context(DuckycapError)
+        .attach("failed to create event stream")?;
+
+    track_keys(&mut DeviceSource::new(events, trace), tracker, redact, sink).await
+}
+
+/// Forward the keys of a recorded trace as if it came from a device
+async fn run_replay(
+    path: &Path,
+    chatter_window: Duration,
+    redact: bool,
+    sink: &mut impl EventSink,
+) -> Result<(), Report<DuckycapError>> {
+    let mut reader = TraceReader::open(path).await?;
+
+    let id = format!("trace:{}", path.display());
+    let tracker = KeyTracker::new(id.clone(), chatter_window, redact);
+    sink.report_devices(&[CaptureDevice {
+        id,
+        name: format!("Replay of {}", path.display()),
+    }])
+    .await;
+
+    info!("Replaying {}", path.display());
+    track_keys(&mut reader, tracker, redact, sink).await
+}
+
+/// Release all held keys and tell the sink the device is gone
+async fn release_device(tracker: &mut KeyTracker, sink: &mut impl EventSink) {
+    for event in tracker.release_all() {
+        sink.send_keys(event).await;
+    }
+    sink.report_devices(&[]).await;
+}
+
+/// Main capture loop, until the source runs out or the daemon is stopped
+async fn track_keys(
+    source: &mut impl InputSource,
+    mut tracker: KeyTracker,
+    redact: bool,
+    sink: &mut impl EventSink,
+) -> Result<(), Report<DuckycapError>> {
+    let mut signals = ShutdownSignals::new()
+        .change_context(DuckycapError)
+        .attach("failed to install signal handlers")?;
+
+    info!(
+        "Listening for key events (chatter window {:?})...",
+        tracker.chatter_window()
+    );
+
+    // Event loop
+    loop {
+        // Wake up when a chatter window ends to report the key's settled state
+        let chatter_deadline = async {
+            match tracker.next_deadline() {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        let next = tokio::select! {
+            signal = signals.recv() => {
+                info!("Received {signal}, releasing device");
+                release_device(&mut tracker, sink).await;
+                source.release();
+                return Ok(());
+            }
+            () = chatter_deadline => {
+                for event in tracker.settle(Instant::now()) {
+                    sink.send_keys(event).await;
+                }
+                continue;
+            }
+            next = source.next_event() => next,
+        };
+
+        let event = match next {
+            Ok(Some(event)) => event,
+            Ok(None) => {
+                // Let the keys settle as they would have on the device
+                while let Some(deadline) = tracker.next_deadline() {
+                    tokio::time::sleep_until(deadline).await;
+                    for event in tracker.settle(Instant::now()) {
+                        sink.send_keys(event).await;
+                    }
+                }
+                info!("End of input, releasing held keys");
+                release_device(&mut tracker, sink).await;
+                return Ok(());
+            }
+            Err(e) => {
+                error!("Error reading events: {e:?}");
+                // Don't leave the keys of a disconnected device held
+                release_device(&mut tracker, sink).await;
+                return Err(e);
+            }
+        };
+
+        if !redact {
+            trace!("event {event:?}");
+        }
+
+        if let Some(event) = tracker.input(&event, Instant::now()) {
+            sink.send_keys(event).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::test_support::key;
+    use ducky_relay::KeyEvent;
+    use evdev::KeyCode;
+    use std::collections::VecDeque;
+
+    #[tokio::test]
+    async fn held_keys_are_released_at_the_end_of_input() {
+        let mut source = VecDeque::from([
+            key(KeyCode::KEY_LEFTCTRL, 1),
+            key(KeyCode::KEY_B, 1),
+            key(KeyCode::KEY_B, 0),
+            key(KeyCode::KEY_A, 1),
+        ]);
+        let tracker = KeyTracker::new("pad".to_string(), Duration::ZERO, false);
+        let mut sink: Vec<KeyEvent> = Vec::new();
+
+        track_keys(&mut source, tracker, false, &mut sink)
+            .await
+            .unwrap();
+
+        let events: Vec<(&str, bool, Vec<&str>)> = sink
+            .iter()
+            .map(|e| {
+                let held = e.held.iter().map(String::as_str).collect();
+                (e.key.as_str(), e.pressed, held)
+            })
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                ("ctrl", true, vec!["ctrl"]),
+                ("b", true, vec!["b", "ctrl"]),
+                ("b", false, vec!["b", "ctrl"]),
+                ("a", true, vec!["a", "ctrl"]),
+                ("ctrl", false, vec!["a", "ctrl"]),
+                ("a", false, vec!["a"]),
+            ]
+        );
+        let sequences: Vec<u64> = sink.iter().map(|e| e.sequence).collect();
+        assert_eq!(sequences, (0..6).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn chatter_settles_before_the_end_of_input() {
+        // A release within the chatter window is held back, then reported
+        let mut source = VecDeque::from([key(KeyCode::KEY_A, 1), key(KeyCode::KEY_A, 0)]);
+        let tracker = KeyTracker::new("pad".to_string(), Duration::from_millis(10), false);
+        let mut sink: Vec<KeyEvent> = Vec::new();
+
+        track_keys(&mut source, tracker, false, &mut sink)
+            .await
+            .unwrap();
+
+        let events: Vec<(&str, bool)> = sink.iter().map(|e| (e.key.as_str(), e.pressed)).collect();
+        assert_eq!(events, vec![("a", true), ("a", false)]);
+    }
+}
@@ -0,0 +1,158 @@
+//! Receivers of key events.
+
+use crate::DuckycapError;
+use ducky_relay::{CaptureDevice, KeyEvent, KeystrokeError, KeystrokeProxy};
+use error_stack::{Report, ResultExt};
+use std::path::{Path, PathBuf};
+use tracing::{error, warn};
+use zlink::unix;
+
+/// Something key events can be sent to
+pub trait EventSink {
+    async fn send_keys(&mut self, event: KeyEvent);
+
+    /// Announce the devices events come from, none once they are released
+    async fn report_devices(&mut self, devices: &[CaptureDevice]);
+}
+
+/// Sends key events to the varlink service
+pub struct VarlinkSink {
+    socket: PathBuf,
+    /// Name of this daemon in the service's status
+    client: String,
+}
+
+impl VarlinkSink {
+    pub fn new(socket: PathBuf) -> Self {
+        Self {
+            socket,
+            client: format!("duckycap[{}]", std::process::id()),
+        }
+    }
+}
+
+impl EventSink for VarlinkSink {
+    async fn send_keys(&mut self, event: KeyEvent) {
+        if let Err(e) = send_keys_to_varlink(&self.socket, &event).await {
+            error!("Failed to send to varlink: {e:?}");
+        }
+    }
+
+    /// Tell the service which devices are grabbed, for its status
+    async fn report_devices(&mut self, devices: &[CaptureDevice]) {
+        if let Err(e) = send_devices_to_varlink(&self.socket, &self.client, devices).await {
+            warn!("Failed to report devices: {e:?}");
+        }
+    }
+}
+
+/// Prints key events to stdout, one JSON object per line
+pub struct StdoutSink;
+
+impl EventSink for StdoutSink {
+    async fn send_keys(&mut self, event: KeyEvent) {
+        match serde_json::to_string(&event) {
+            Ok(line) => println!("{line}"),
+            Err(e) => error!("Failed to serialize key event: {e}"),
+        }
+    }
+
+    async fn report_devices(&mut self, _devices: &[CaptureDevice]) {}
+}
+
+/// Collects key events, for tests
+impl EventSink for Vec<KeyEvent> {
+    async fn send_keys(&mut self, event: KeyEvent) {
+        self.push(event);
+    }
+
+    async fn report_devices(&mut self, _devices: &[CaptureDevice]) {}
+}
+
+/// Send a key event to varlink service using zlink proxy
+async fn send_keys_to_varlink(
+    socket: &Path,
+    event: &KeyEvent,
+) -> Result<(), Report<DuckycapError>> {
+    if event.held.is_empty() {
+        return Ok(());
+    }
+
+    // Connect to varlink socket using zlink::unix::connect
+    let mut conn = unix::connect(socket)
+        .await
+        .change_context(DuckycapError)
+        .attach_with(|| {
+            format!(
+                "failed to connect to varlink socket at '{}'",
+                socket.display()
+            )
+        })?;
+
+    // Use the proxy-generated method directly on the connection
+    let result = conn
+        .send_keys_v2(event)
+        .await
+        .change_context(DuckycapError)
+        .attach("failed to send keystroke event via varlink")?;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(KeystrokeError::InvalidKey { message }) => {
+            if event.redact {
+                warn!("Invalid key error");
+            } else {
+                warn!("Invalid key error: {message}");
+            }
+            Ok(())
+        }
+        // The event got through, only its command was dropped
+        Err(KeystrokeError::RateLimited { message }) => {
+            warn!("Varlink service is rate limiting commands: {message}");
+            Ok(())
+        }
+        // Every further event will fail the same way until this is fixed
+        Err(KeystrokeError::PermissionDenied { method }) => Err(Report::new(DuckycapError)
+            .attach(format!(
+                "not allowed to call {method}, this daemon's user must be in the varlink service's [access.send] allowlist"
+            ))),
+        Err(KeystrokeError::ConfigNotLoaded { message }) => Err(Report::new(DuckycapError)
+            .attach(format!("varlink service has no config: {message}"))),
+        // Only returned for options and methods this daemon doesn't use
+        Err(
+            KeystrokeError::UnknownLayer { .. }
+            | KeystrokeError::UnknownBinding { .. }
+            | KeystrokeError::CommandFailed { .. }
+            | KeystrokeError::CommandTimeout { .. }
+            | KeystrokeError::UnknownCommand { .. },
+        ) => {
+            warn!("Unexpected error from the varlink service");
+            Ok(())
+        }
+    }
+}
+
+/// Tell the varlink service which devices this daemon has grabbed
+async fn send_devices_to_varlink(
+    socket: &Path,
+    client: &str,
+    devices: &[CaptureDevice],
+) -> Result<(), Report<DuckycapError>> {
+    let mut conn = unix::connect(socket)
+        .await
+        .change_context(DuckycapError)
+        .attach_with(|| {
+            format!(
+                "failed to connect to varlink socket at '{}'",
+                socket.display()
+            )
+        })?;
+
+    conn.report_devices(client, devices)
+        .await
+        .change_context(DuckycapError)
+        .attach("failed to report devices via varlink")?
+        .map_err(|e| {
+            Report::new(DuckycapError).attach(format!("service rejected the devices: {e:?}"))
+        })
+}
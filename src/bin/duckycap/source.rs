@@ -0,0 +1,64 @@
+//! Sources of input events.
+
+use crate::DuckycapError;
+use crate::trace::{TraceEvent, TraceWriter};
+use error_stack::{Report, ResultExt};
+use evdev::EventStream;
+use std::collections::VecDeque;
+use tracing::{error, warn};
+
+/// Something input events can be read from
+pub trait InputSource {
+    /// Wait for the next event, `None` once there are no more
+    ///
+    /// Cancelling the wait must not lose an event.
+    async fn next_event(&mut self) -> Result<Option<TraceEvent>, Report<DuckycapError>>;
+
+    /// Give up the source, e.g. the grab on a device
+    fn release(&mut self) {}
+}
+
+/// A grabbed device, and the trace its events are recorded to
+pub struct DeviceSource {
+    events: EventStream,
+    trace: Option<TraceWriter>,
+}
+
+impl DeviceSource {
+    pub fn new(events: EventStream, trace: Option<TraceWriter>) -> Self {
+        Self { events, trace }
+    }
+}
+
+impl InputSource for DeviceSource {
+    async fn next_event(&mut self) -> Result<Option<TraceEvent>, Report<DuckycapError>> {
+        let event: TraceEvent = self
+            .events
+            .next_event()
+            .await
+            .inspect_err(|_| warn!("Device may have been disconnected"))
+            .change_context(DuckycapError)
+            .attach("error reading events")?
+            .into();
+        if let Some(writer) = &mut self.trace {
+            if let Err(e) = writer.write(&event) {
+                error!("Failed to write to the trace, recording stopped: {e}");
+                self.trace = None;
+            }
+        }
+        Ok(Some(event))
+    }
+
+    fn release(&mut self) {
+        if let Err(e) = self.events.device_mut().ungrab() {
+            error!("Failed to release device grab: {e}");
+        }
+    }
+}
+
+/// Events given up front, delivered without delay
+impl InputSource for VecDeque<TraceEvent> {
+    async fn next_event(&mut self) -> Result<Option<TraceEvent>, Report<DuckycapError>> {
+        Ok(self.pop_front())
+    }
+}
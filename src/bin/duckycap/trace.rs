@@ -0,0 +1,168 @@
+//! Input event traces.
+//!
+//! A trace is a JSONL file with one raw evdev event per line, as read from
+//! the device before any filtering. Replaying a trace reproduces the
+//! original timing, so the chatter filter and the key tracking see the same
+//! events they saw on the hardware.
+
+use crate::DuckycapError;
+use crate::source::InputSource;
+use ducky_relay::timestamp_usec;
+use error_stack::{Report, ResultExt};
+use evdev::InputEvent;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::time::Instant;
+
+/// A raw input event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceEvent {
+    /// Time the kernel reported the event in microseconds since the Unix epoch
+    pub time_usec: u64,
+    /// Event type, e.g. 1 for `EV_KEY`
+    #[serde(rename = "type")]
+    pub event_type: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl TraceEvent {
+    pub fn timestamp(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_micros(self.time_usec)
+    }
+}
+
+impl From<InputEvent> for TraceEvent {
+    fn from(event: InputEvent) -> Self {
+        Self {
+            time_usec: timestamp_usec(event.timestamp()),
+            event_type: event.event_type().0,
+            code: event.code(),
+            value: event.value(),
+        }
+    }
+}
+
+/// Writes events to a trace file
+pub struct TraceWriter {
+    file: LineWriter<File>,
+}
+
+impl TraceWriter {
+    /// Create a trace file only its owner can read, replacing an existing one
+    pub fn create(path: &Path) -> Result<Self, Report<DuckycapError>> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)
+            .change_context(DuckycapError)
+            .attach_with(|| format!("failed to create trace file '{}'", path.display()))?;
+        Ok(Self {
+            file: LineWriter::new(file),
+        })
+    }
+
+    /// Append an event, flushing it so nothing is lost if the daemon dies
+    pub fn write(&mut self, event: &TraceEvent) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.file, event)?;
+        self.file.write_all(b"\n")
+    }
+}
+
+/// Reads the events of a trace file at the pace they were recorded
+pub struct TraceReader {
+    path: PathBuf,
+    lines: Lines<BufReader<tokio::fs::File>>,
+    line: usize,
+    /// Time of the first event, and when it was replayed
+    start: Option<(u64, Instant)>,
+    /// Event read but not yet due
+    pending: Option<TraceEvent>,
+}
+
+impl TraceReader {
+    pub async fn open(path: &Path) -> Result<Self, Report<DuckycapError>> {
+        let file = tokio::fs::File::open(path)
+            .await
+            .change_context(DuckycapError)
+            .attach_with(|| format!("failed to open trace file '{}'", path.display()))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            lines: BufReader::new(file).lines(),
+            line: 0,
+            start: None,
+            pending: None,
+        })
+    }
+
+    /// Read the next event, skipping empty lines
+    async fn read_event(&mut self) -> Result<Option<TraceEvent>, Report<DuckycapError>> {
+        loop {
+            let Some(line) = self
+                .lines
+                .next_line()
+                .await
+                .change_context(DuckycapError)
+                .attach_with(|| format!("failed to read trace file '{}'", self.path.display()))?
+            else {
+                return Ok(None);
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            return serde_json::from_str(&line)
+                .map(Some)
+                .change_context(DuckycapError)
+                .attach_with(|| format!("{}:{}: invalid event", self.path.display(), self.line));
+        }
+    }
+}
+
+/// Events are delivered once they are due and timestamped with the time they
+/// are replayed at, so the service sees them as current
+impl InputSource for TraceReader {
+    async fn next_event(&mut self) -> Result<Option<TraceEvent>, Report<DuckycapError>> {
+        let event = match self.pending {
+            Some(event) => event,
+            None => match self.read_event().await? {
+                Some(event) => *self.pending.insert(event),
+                None => return Ok(None),
+            },
+        };
+
+        let (first, started) = *self.start.get_or_insert((event.time_usec, Instant::now()));
+        let offset = Duration::from_micros(event.time_usec.saturating_sub(first));
+        tokio::time::sleep_until(started + offset).await;
+        self.pending = None;
+
+        Ok(Some(TraceEvent {
+            time_usec: timestamp_usec(SystemTime::now()),
+            ..event
+        }))
+    }
+}
+
+/// Events for the tests of other modules
+#[cfg(test)]
+pub(crate) mod test_support {
+    use super::TraceEvent;
+
+    /// A key event of `key` with `value` 0 for released, 1 for pressed or 2
+    /// for repeated
+    pub(crate) fn key(key: evdev::KeyCode, value: i32) -> TraceEvent {
+        TraceEvent {
+            time_usec: 1_760_000_000_000_000,
+            event_type: evdev::EventType::KEY.0,
+            code: key.code(),
+            value,
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! Tracking of held keys.
+//!
+//! Turns raw input events into the key events sent to the varlink service,
+//! without doing any I/O, so the chord and release logic can be tested
+//! without input devices.
+
+use crate::trace::TraceEvent;
+use ducky_relay::{KeyEvent, key_to_name, log_keys, timestamp_usec};
+use evdev::{EventType, KeyCode};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+use tokio::time::Instant;
+use tracing::debug;
+
+/// Suppresses contact bounce ("chatter") of individual keys
+///
+/// A key's first state change is reported right away. Further changes within
+/// the chatter window are held back, and once the window has passed the key's
+/// latest state is reported if it differs from the last reported one.
+struct ChatterFilter {
+    window: Duration,
+    /// Keys within their chatter window: when the window ends and the key's latest state
+    settling: HashMap<KeyCode, (Instant, bool)>,
+}
+
+impl ChatterFilter {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            settling: HashMap::new(),
+        }
+    }
+
+    /// Start the chatter window of a key after reporting a state change
+    fn start(&mut self, key: KeyCode, pressed: bool, now: Instant) {
+        if !self.window.is_zero() {
+            self.settling.insert(key, (now + self.window, pressed));
+        }
+    }
+
+    /// Check whether a key event falls within the key's chatter window,
+    /// remembering its state for when the window ends
+    fn suppress(&mut self, key: KeyCode, pressed: bool, now: Instant) -> bool {
+        match self.settling.get_mut(&key) {
+            Some((until, state)) if now < *until => {
+                *state = pressed;
+                true
+            }
+            Some(_) => {
+                // The window has passed, this event supersedes the held back state
+                self.settling.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// End of the earliest chatter window
+    fn next_deadline(&self) -> Option<Instant> {
+        self.settling.values().map(|(until, _)| *until).min()
+    }
+
+    /// Remove keys whose chatter window has passed, returning their latest state
+    fn settle(&mut self, now: Instant) -> Vec<(KeyCode, bool)> {
+        let mut settled = Vec::new();
+        self.settling.retain(|key, (until, state)| {
+            if *until <= now {
+                settled.push((*key, *state));
+                false
+            } else {
+                true
+            }
+        });
+        settled
+    }
+}
+
+/// Held keys of a device, and the key events their changes produce
+pub struct KeyTracker {
+    /// Currently held keys
+    held_keys: HashSet<KeyCode>,
+    chatter: ChatterFilter,
+    device_id: String,
+    /// Every key event gets the next sequence number
+    sequence: u64,
+    /// Leave key names out of logs, here and in the service
+    redact: bool,
+}
+
+impl KeyTracker {
+    pub fn new(device_id: String, chatter_window: Duration, redact: bool) -> Self {
+        Self {
+            held_keys: HashSet::new(),
+            chatter: ChatterFilter::new(chatter_window),
+            device_id,
+            sequence: 0,
+            redact,
+        }
+    }
+
+    pub fn chatter_window(&self) -> Duration {
+        self.chatter.window
+    }
+
+    /// Describe a key for logs
+    fn describe(&self, key: KeyCode) -> String {
+        let name = key_to_name(key).unwrap_or_else(|| format!("{key:?}"));
+        log_keys(&[name], self.redact).to_string()
+    }
+
+    /// Check whether a key event changes the set of held keys
+    fn is_edge(&self, key: KeyCode, pressed: bool) -> bool {
+        self.held_keys.contains(&key) != pressed
+    }
+
+    /// Handle an input event that arrived at `now`, returning the key event
+    /// to send if it changed a key
+    pub fn input(&mut self, event: &TraceEvent, now: Instant) -> Option<KeyEvent> {
+        // Only process key events
+        if event.event_type != EventType::KEY.0 {
+            return None;
+        }
+
+        // Keys without a name can't be bound, so don't track them.
+        // Otherwise they would produce duplicate edges for the
+        // keys that are held alongside them.
+        let key = KeyCode::new(event.code);
+        key_to_name(key)?;
+
+        // Handle key press (value == 1), release (value == 0) and
+        // repeat (value == 2). Only send edges that change the held set.
+        match event.value {
+            0 | 1 => {
+                let pressed = event.value == 1;
+                if self.chatter.suppress(key, pressed, now) {
+                    debug!(key = %self.describe(key), pressed, "Ignoring chatter");
+                    None
+                } else if self.is_edge(key, pressed) {
+                    self.chatter.start(key, pressed, now);
+                    self.key_event(key, pressed, false, event.timestamp())
+                } else {
+                    None
+                }
+            }
+            // Whether a binding repeats is up to the service
+            2 => {
+                if self.held_keys.contains(&key) && !self.chatter.suppress(key, true, now) {
+                    self.key_event(key, true, true, event.timestamp())
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// End of the earliest chatter window, when `settle` has work to do
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.chatter.next_deadline()
+    }
+
+    /// Return the key events of keys whose chatter window has passed by
+    /// `now` and whose state changed within it
+    pub fn settle(&mut self, now: Instant) -> Vec<KeyEvent> {
+        let mut events = Vec::new();
+        for (key, pressed) in self.chatter.settle(now) {
+            if self.is_edge(key, pressed) {
+                debug!(key = %self.describe(key), "Key settled after chatter");
+                self.chatter.start(key, pressed, now);
+                events.extend(self.key_event(key, pressed, false, SystemTime::now()));
+            }
+        }
+        events
+    }
+
+    /// Return release events for all held keys
+    pub fn release_all(&mut self) -> Vec<KeyEvent> {
+        let mut held: Vec<KeyCode> = self.held_keys.iter().copied().collect();
+        held.sort_by_key(|key| key.code());
+        held.into_iter()
+            .filter_map(|key| self.key_event(key, false, false, SystemTime::now()))
+            .collect()
+    }
+
+    /// Update the held keys and build the key event for a change
+    fn key_event(
+        &mut self,
+        key: KeyCode,
+        pressed: bool,
+        repeat: bool,
+        timestamp: SystemTime,
+    ) -> Option<KeyEvent> {
+        // Keys without a name are never reported
+        let name = key_to_name(key)?;
+
+        if pressed {
+            self.held_keys.insert(key);
+        }
+
+        let event = KeyEvent {
+            key: name,
+            pressed,
+            repeat,
+            held: get_key_names(&self.held_keys),
+            timestamp_usec: timestamp_usec(timestamp),
+            sequence: self.sequence,
+            device: self.device_id.clone(),
+            redact: self.redact,
+            strict: false,
+        };
+        self.sequence += 1;
+
+        let edge = match (pressed, repeat) {
+            (true, false) => "press",
+            (true, true) => "repeat",
+            (false, _) => "release",
+        };
+        debug!(
+            keys = %log_keys(&event.held, self.redact),
+            key = %self.describe(key),
+            sequence = event.sequence,
+            "Key {edge}"
+        );
+
+        // The key up event is built BEFORE removing the key from tracking,
+        // so its held keys still contain the released key
+        if !pressed {
+            self.held_keys.remove(&key);
+        }
+
+        Some(event)
+    }
+}
+
+/// Convert held keys to human-readable names
+fn get_key_names(keys: &HashSet<KeyCode>) -> Vec<String> {
+    let mut names: Vec<String> = keys.iter().filter_map(|k| key_to_name(*k)).collect();
+
+    // Sort for consistent ordering
+    names.sort();
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::trace::test_support::key;
+
+    const CHATTER: Duration = Duration::from_millis(10);
+
+    /// Key, pressed, repeat and held keys of an event
+    fn summary(event: &KeyEvent) -> (&str, bool, bool, Vec<&str>) {
+        (
+            event.key.as_str(),
+            event.pressed,
+            event.repeat,
+            event.held.iter().map(String::as_str).collect(),
+        )
+    }
+
+    #[test]
+    fn chord_holds_all_pressed_keys() {
+        let mut tracker = KeyTracker::new("pad".to_string(), CHATTER, false);
+        let now = Instant::now();
+
+        let ctrl = tracker.input(&key(KeyCode::KEY_LEFTCTRL, 1), now).unwrap();
+        let a = tracker.input(&key(KeyCode::KEY_A, 1), now).unwrap();
+
+        assert_eq!(summary(&ctrl), ("ctrl", true, false, vec!["ctrl"]));
+        assert_eq!(summary(&a), ("a", true, false, vec!["a", "ctrl"]));
+        assert_eq!((ctrl.sequence, a.sequence), (0, 1));
+        assert_eq!(a.device, "pad");
+    }
+
+    #[test]
+    fn release_is_sent_before_the_key_is_removed() {
+        let mut tracker = KeyTracker::new("pad".to_string(), Duration::ZERO, false);
+        let now = Instant::now();
+
+        tracker.input(&key(KeyCode::KEY_LEFTCTRL, 1), now);
+        tracker.input(&key(KeyCode::KEY_A, 1), now);
+        let a = tracker.input(&key(KeyCode::KEY_A, 0), now).unwrap();
+        let ctrl = tracker.input(&key(KeyCode::KEY_LEFTCTRL, 0), now).unwrap();
+
+        assert_eq!(summary(&a), ("a", false, false, vec!["a", "ctrl"]));
+        assert_eq!(summary(&ctrl), ("ctrl", false, false, vec!["ctrl"]));
+        assert!(tracker.release_all().is_empty());
+    }
+
+    #[test]
+    fn only_edges_are_sent() {
+        let mut tracker = KeyTracker::new("pad".to_string(), Duration::ZERO, false);
+        let now = Instant::now();
+
+        assert!(tracker.input(&key(KeyCode::KEY_A, 0), now).is_none());
+        assert!(tracker.input(&key(KeyCode::KEY_A, 1), now).is_some());
+        assert!(tracker.input(&key(KeyCode::KEY_A, 1), now).is_none());
+    }
+
+    #[test]
+    fn repeats_need_a_held_key() {
+        let mut tracker = KeyTracker::new("pad".to_string(), Duration::ZERO, false);
+        let now = Instant::now();
+
+        assert!(tracker.input(&key(KeyCode::KEY_A, 2), now).is_none());
+        tracker.input(&key(KeyCode::KEY_A, 1), now);
+        let repeat = tracker.input(&key(KeyCode::KEY_A, 2), now).unwrap();
+
+        assert_eq!(summary(&repeat), ("a", true, true, vec!["a"]));
+    }
+
+    #[test]
+    fn chatter_is_suppressed_until_the_key_settles() {
+        let mut tracker = KeyTracker::new("pad".to_string(), CHATTER, false);
+        let now = Instant::now();
+
+        // A press that bounces, then stays pressed
+        assert!(tracker.input(&key(KeyCode::KEY_A, 1), now).is_some());
+        let bounce = now + Duration::from_millis(3);
+        assert!(tracker.input(&key(KeyCode::KEY_A, 0), bounce).is_none());
+        assert!(tracker.input(&key(KeyCode::KEY_A, 1), bounce).is_none());
+
+        assert_eq!(tracker.next_deadline(), Some(now + CHATTER));
+        assert!(tracker.settle(now + CHATTER).is_empty());
+
+        // A spurious release within the window is reported once it settles
+        let release = now + Duration::from_millis(20);
+        assert!(tracker.input(&key(KeyCode::KEY_A, 0), release).is_some());
+        assert!(
+            tracker
+                .input(&key(KeyCode::KEY_A, 1), release + Duration::from_millis(1))
+                .is_none()
+        );
+        let settled = tracker.settle(release + CHATTER);
+        assert_eq!(settled.len(), 1);
+        assert_eq!(summary(&settled[0]), ("a", true, false, vec!["a"]));
+    }
+
+    #[test]
+    fn keys_without_a_name_and_other_events_are_ignored() {
+        let mut tracker = KeyTracker::new("pad".to_string(), Duration::ZERO, false);
+        let now = Instant::now();
+
+        assert!(tracker.input(&key(KeyCode::BTN_LEFT, 1), now).is_none());
+        let sync = TraceEvent {
+            event_type: EventType::SYNCHRONIZATION.0,
+            ..key(KeyCode::KEY_A, 1)
+        };
+        assert!(tracker.input(&sync, now).is_none());
+        assert!(tracker.release_all().is_empty());
+    }
+
+    #[test]
+    fn release_all_releases_every_held_key() {
+        let mut tracker = KeyTracker::new("pad".to_string(), Duration::ZERO, true);
+        let now = Instant::now();
+
+        tracker.input(&key(KeyCode::KEY_LEFTCTRL, 1), now);
+        tracker.input(&key(KeyCode::KEY_A, 1), now);
+        let released = tracker.release_all();
+
+        let summaries: Vec<_> = released.iter().map(summary).collect();
+        assert_eq!(
+            summaries,
+            vec![
+                ("ctrl", false, false, vec!["a", "ctrl"]),
+                ("a", false, false, vec!["a"]),
+            ]
+        );
+        assert!(released.iter().all(|event| event.redact));
+        assert!(tracker.release_all().is_empty());
+    }
+}
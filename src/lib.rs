@@ -1,8 +1,20 @@
 //! Ducky Relay Shared Library
 //!
-//! Common types and constants for the ducky-relay varlink service and client.
+//! Common types and constants for the ducky-relay varlink service and client,
+//! including the table of key names both of them use, and the varlink service
+//! itself.
 
+pub mod service;
+
+use evdev::KeyCode;
 use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::signal::unix::{Signal, SignalKind, signal};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use zlink::{ReplyError, introspect};
 
 // ============================================================================
@@ -12,16 +24,479 @@ use zlink::{ReplyError, introspect};
 /// Default varlink socket path
 pub const VARLINK_SOCKET: &str = "/run/duckycap.varlink";
 
+/// File name of the socket of a per-user service in `$XDG_RUNTIME_DIR`
+pub const USER_SOCKET_NAME: &str = "duckycap.varlink";
+
+/// Socket path for the user this process runs as
+///
+/// Root uses [`VARLINK_SOCKET`]. Other users use [`USER_SOCKET_NAME`] in
+/// `$XDG_RUNTIME_DIR`, where the per-user units put it, unless it isn't set.
+pub fn default_socket() -> PathBuf {
+    if !rustix::process::geteuid().is_root() {
+        if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR") {
+            return Path::new(&dir).join(USER_SOCKET_NAME);
+        }
+    }
+    PathBuf::from(VARLINK_SOCKET)
+}
+
+// ============================================================================
+// Logging
+// ============================================================================
+
+/// Log filter used when neither `--log-level` nor `RUST_LOG` is set
+///
+/// Clients connect once per key event, so zlink's warnings about closed
+/// connections are hidden.
+pub const DEFAULT_LOG_FILTER: &str = "info,zlink_core=error";
+
+/// Set up logging to stderr, or natively to the systemd journal
+///
+/// `level` takes precedence over `RUST_LOG`. Both accept filter directives
+/// such as `debug` or `duckycap_varlink=trace`. In the journal, event fields
+/// are stored as upper-case journal fields, e.g. `KEYS=` or `EXIT_CODE=`.
+///
+/// # Errors
+///
+/// Fails if the filter is invalid or the journal can't be reached.
+pub fn init_logging(level: Option<&str>, journald: bool) -> Result<(), String> {
+    let filter =
+        match level {
+            Some(level) => EnvFilter::try_new(level)
+                .map_err(|e| format!("Invalid log level '{level}': {e}"))?,
+            None => EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER)),
+        };
+
+    let registry = tracing_subscriber::registry().with(filter);
+    if journald {
+        let journald = tracing_journald::layer()
+            .map_err(|e| format!("Failed to connect to the journal: {e}"))?
+            .with_field_prefix(None);
+        registry.with(journald).init();
+    } else {
+        registry
+            .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+            .init();
+    }
+
+    Ok(())
+}
+
+/// Whether key names are left out of logs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Redact {
+    /// Redact the keys of devices not marked as macro pads
+    #[default]
+    Auto,
+    /// Always redact key names
+    Always,
+    /// Never redact key names
+    Never,
+}
+
+impl Redact {
+    /// Decide whether to redact, given what `Auto` decides for the keys' source
+    pub fn resolve(self, auto: bool) -> bool {
+        match self {
+            Self::Auto => auto,
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+/// Key names formatted for logs, or only their count when redacted
+pub struct LoggedKeys<'a, S> {
+    keys: &'a [S],
+    redact: bool,
+}
+
+/// Format key names for logs, replacing them by their count if `redact` is set
+pub fn log_keys<S: AsRef<str>>(keys: &[S], redact: bool) -> LoggedKeys<'_, S> {
+    LoggedKeys { keys, redact }
+}
+
+impl<S: AsRef<str>> fmt::Display for LoggedKeys<'_, S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.redact {
+            return write!(f, "<{} redacted>", self.keys.len());
+        }
+        for (i, key) in self.keys.iter().enumerate() {
+            if i > 0 {
+                f.write_str("+")?;
+            }
+            f.write_str(key.as_ref())?;
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Signals
+// ============================================================================
+
+/// SIGTERM and SIGINT handlers used to shut down cleanly
+pub struct ShutdownSignals {
+    terminate: Signal,
+    interrupt: Signal,
+}
+
+impl ShutdownSignals {
+    /// Install the signal handlers
+    ///
+    /// # Errors
+    ///
+    /// Fails if a signal handler can't be registered.
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self {
+            terminate: signal(SignalKind::terminate())?,
+            interrupt: signal(SignalKind::interrupt())?,
+        })
+    }
+
+    /// Wait for the next shutdown signal, returning its name
+    pub async fn recv(&mut self) -> &'static str {
+        tokio::select! {
+            _ = self.terminate.recv() => "SIGTERM",
+            _ = self.interrupt.recv() => "SIGINT",
+        }
+    }
+}
+
+// ============================================================================
+// Key Names
+// ============================================================================
+
+/// Highest key code the kernel defines (`KEY_MAX`)
+const KEY_MAX: u16 = 0x2ff;
+
+/// Convert a `KeyCode` to a human-readable name
+pub fn key_to_name(key: KeyCode) -> Option<String> {
+    key_name(key.code()).map(str::to_string)
+}
+
+/// Check whether a name is in the key table
+pub fn is_key_name(name: &str) -> bool {
+    (0..=KEY_MAX).any(|code| key_name(code) == Some(name))
+}
+
+/// Human-readable name of a key code
+#[allow(clippy::match_same_arms)]
+#[allow(clippy::too_many_lines)]
+fn key_name(code: u16) -> Option<&'static str> {
+    // Map common key codes to human-readable names
+    // Based on Linux input event codes
+    let name = match code {
+        // Letters
+        16 => "q",
+        17 => "w",
+        18 => "e",
+        19 => "r",
+        20 => "t",
+        21 => "y",
+        22 => "u",
+        23 => "i",
+        24 => "o",
+        25 => "p",
+        30 => "a",
+        31 => "s",
+        32 => "d",
+        33 => "f",
+        34 => "g",
+        35 => "h",
+        36 => "j",
+        37 => "k",
+        38 => "l",
+        44 => "z",
+        45 => "x",
+        46 => "c",
+        47 => "v",
+        48 => "b",
+        49 => "n",
+        50 => "m",
+
+        // Numbers
+        2 => "1",
+        3 => "2",
+        4 => "3",
+        5 => "4",
+        6 => "5",
+        7 => "6",
+        8 => "7",
+        9 => "8",
+        10 => "9",
+        11 => "0",
+
+        // Function keys
+        59 => "f1",
+        60 => "f2",
+        61 => "f3",
+        62 => "f4",
+        63 => "f5",
+        64 => "f6",
+        65 => "f7",
+        66 => "f8",
+        67 => "f9",
+        68 => "f10",
+        87 => "f11",
+        88 => "f12",
+
+        // Modifiers
+        29 => "ctrl",
+        97 => "ctrl", // Left/Right Ctrl
+        42 => "shift",
+        54 => "shift", // Left/Right Shift
+        56 => "alt",
+        100 => "alt", // Left/Right Alt
+        125 => "meta",
+        126 => "meta", // Left/Right Meta/Super
+
+        // Special keys
+        1 => "escape",
+        14 => "backspace",
+        15 => "tab",
+        28 => "enter",
+        57 => "space",
+        58 => "capslock",
+        111 => "delete",
+        110 => "home",
+        115 => "end",
+        112 => "pageup",
+        117 => "pagedown",
+
+        // Arrow keys
+        103 => "up",
+        108 => "down",
+        105 => "left",
+        106 => "right",
+
+        // Symbols
+        12 => "minus",
+        13 => "equal",
+        26 => "leftbracket",
+        27 => "rightbracket",
+        39 => "semicolon",
+        40 => "apostrophe",
+        41 => "grave",
+        43 => "backslash",
+        51 => "comma",
+        52 => "dot",
+        53 => "slash",
+
+        // Numpad
+        69 => "numlock",
+        71 => "kp7",
+        72 => "kp8",
+        73 => "kp9",
+        75 => "kp4",
+        76 => "kp5",
+        77 => "kp6",
+        79 => "kp1",
+        80 => "kp2",
+        81 => "kp3",
+        82 => "kp0",
+        83 => "kpdot",
+        78 => "kpplus",
+        74 => "kpminus",
+        55 => "kpasterisk",
+        98 => "kpslash",
+        96 => "kpenter",
+
+        // Other
+        99 => "sysrq",
+        119 => "pause",
+        120 => "scrolllock",
+        116 => "power",
+        142 => "sleep",
+
+        // Unknown - return code number
+        _ => {
+            return None;
+        }
+    };
+
+    Some(name)
+}
+
 // ============================================================================
 // Message Types
 // ============================================================================
 
-/// Response for `SendKeys` method
+/// Convert a timestamp to microseconds since the Unix epoch
+pub fn timestamp_usec(timestamp: SystemTime) -> u64 {
+    timestamp
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::try_from(d.as_micros()).unwrap_or(u64::MAX))
+}
+
+/// A single key edge, sent with the `SendKeysV2` method
+#[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
+#[allow(clippy::struct_excessive_bools)]
+pub struct KeyEvent {
+    /// Key that was pressed or released
+    pub key: String,
+    /// `true` for a key down event, `false` for a key up event
+    pub pressed: bool,
+    /// `true` for an auto-repeat event of a key that is still held
+    #[serde(default)]
+    pub repeat: bool,
+    /// All keys held at the time of the event, including a key being released
+    pub held: Vec<String>,
+    /// Kernel timestamp of the event in microseconds since the Unix epoch
+    pub timestamp_usec: u64,
+    /// Sequence number, incremented by the sender for every event of a device
+    pub sequence: u64,
+    /// Identifier of the input device the event came from
+    pub device: String,
+    /// Key names of the event must be left out of logs, e.g. because the
+    /// device isn't a macro pad. Assumed when not given.
+    #[serde(default = "default_redact")]
+    pub redact: bool,
+    /// Fail with `UnknownBinding` if a pressed key combination has no binding
+    #[serde(default)]
+    pub strict: bool,
+}
+
+fn default_redact() -> bool {
+    true
+}
+
+/// Response for `SendKeys` and `SendKeysV2` methods
 #[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
 pub struct SendKeysResponse {
     pub success: bool,
     pub keys: Vec<String>,
     pub pressed: bool,
+    /// Steps of a key sequence entered so far, if one is in progress
+    #[serde(default)]
+    pub sequence: Option<Vec<String>>,
+    /// IDs of the commands the event started, to follow with `GetCommand`
+    #[serde(default)]
+    pub commands: Vec<u64>,
+}
+
+/// Response for `GetLayer` and `SetLayer` methods
+#[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
+pub struct LayerResponse {
+    /// Layer currently used for key lookups
+    pub layer: String,
+    /// Names of all configured layers
+    pub layers: Vec<String>,
+}
+
+/// An input device grabbed by a capture daemon
+#[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
+pub struct CaptureDevice {
+    /// Identifier used as `device` in `SendKeysV2` events
+    pub id: String,
+    /// Name the device reports
+    pub name: String,
+}
+
+/// A capture daemon and the devices it reported
+#[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
+pub struct CaptureClient {
+    /// Name identifying the capture daemon, e.g. `duckycap[1234]`
+    pub client: String,
+    /// Devices the capture daemon has grabbed
+    pub devices: Vec<CaptureDevice>,
+    /// Seconds since the capture daemon reported its devices or sent an event
+    pub last_seen_secs: u64,
+}
+
+/// The most recent error the service ran into
+#[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
+pub struct ErrorReport {
+    pub message: String,
+    /// Time of the error in microseconds since the Unix epoch
+    pub timestamp_usec: u64,
+}
+
+/// Response for the `GetStatus` method
+#[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
+pub struct StatusResponse {
+    /// Seconds since the service started
+    pub uptime_secs: u64,
+    /// Path of the loaded config file
+    pub config: String,
+    /// Time the config file was loaded in microseconds since the Unix epoch
+    pub config_loaded_usec: u64,
+    /// Number of bindings and sequences in all layers
+    pub bindings: u64,
+    /// Layer currently used for key lookups
+    pub layer: String,
+    /// Capture daemons that reported their devices
+    pub clients: Vec<CaptureClient>,
+    /// Commands that are still running
+    pub running_commands: u64,
+    /// Seconds until the service exits for being idle at the earliest,
+    /// if the idle timeout is enabled
+    pub idle_exit_secs: Option<u64>,
+    pub last_error: Option<ErrorReport>,
+    /// Whether commands are logged and recorded instead of executed
+    pub dry_run: bool,
+}
+
+/// What became of a command triggered by a key event
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, introspect::Type)]
+pub enum CommandOutcome {
+    /// The command was started
+    Started,
+    /// The command wasn't started because dry-run mode is on
+    DryRun,
+    /// The command wasn't started because it hit a limit
+    RateLimited,
+    /// The command finished with exit code 0
+    Succeeded,
+    /// The command was handed to the user's systemd manager, which started
+    /// it as a unit; how it exits is only logged by the manager
+    Launched,
+    /// The command couldn't be started, failed or was killed
+    Failed,
+}
+
+/// What happened to a command triggered by a key event
+#[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
+pub struct CommandRecord {
+    /// ID of a started command, as returned by `SendKeysV2`
+    #[serde(default)]
+    pub id: Option<u64>,
+    /// Identifier of the binding, e.g. `base#3`
+    pub binding: String,
+    pub command: String,
+    /// Keys that triggered the command, left out if key names are redacted
+    pub keys: Option<Vec<String>>,
+    pub outcome: CommandOutcome,
+    /// Why the command failed, for `Failed`
+    #[serde(default)]
+    pub message: Option<String>,
+    /// Time of the outcome in microseconds since the Unix epoch
+    pub timestamp_usec: u64,
+}
+
+/// Response for the `GetHistory` method
+#[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
+pub struct HistoryResponse {
+    /// Most recent records of triggered and finished commands, oldest first
+    pub commands: Vec<CommandRecord>,
+}
+
+/// Response for the `GetCommand` method
+#[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
+pub struct CommandStatus {
+    pub id: u64,
+    /// Identifier of the binding that started the command
+    pub binding: String,
+    /// `Started` while the command runs, then how it finished
+    pub outcome: CommandOutcome,
+}
+
+/// Reply of the `Monitor` method
+#[derive(Debug, Clone, Serialize, Deserialize, introspect::Type)]
+pub struct MonitorEvent {
+    /// The triggered command, missing if none was triggered yet
+    pub command: Option<CommandRecord>,
 }
 
 // ============================================================================
@@ -32,7 +507,24 @@ pub struct SendKeysResponse {
 #[derive(Debug, Clone, PartialEq, ReplyError, introspect::ReplyError)]
 #[zlink(interface = "io.ducky.Keystroke")]
 pub enum KeystrokeError {
+    /// The keys of an event are missing or inconsistent
     InvalidKey { message: String },
+    /// No layer with that name is configured
+    UnknownLayer { layer: String },
+    /// A pressed key combination has no binding, with `strict` set
+    UnknownBinding { keys: Vec<String> },
+    /// The caller isn't allowed to use the method
+    PermissionDenied { method: String },
+    /// A command wasn't started because it hit a limit
+    RateLimited { message: String },
+    /// The service couldn't load its config file and has no bindings
+    ConfigNotLoaded { message: String },
+    /// The command asked about failed
+    CommandFailed { binding: String, message: String },
+    /// The command asked about was still running when the caller's time was up
+    CommandTimeout { binding: String },
+    /// No command with that ID was started, or it's too long ago
+    UnknownCommand { id: u64 },
 }
 
 // ============================================================================
@@ -46,5 +538,44 @@ pub trait KeystrokeProxy {
         &mut self,
         keys: &[&str],
         pressed: bool,
+        key: Option<&str>,
+    ) -> zlink::Result<Result<SendKeysResponse, KeystrokeError>>;
+
+    async fn send_keys_v2(
+        &mut self,
+        event: &KeyEvent,
     ) -> zlink::Result<Result<SendKeysResponse, KeystrokeError>>;
+
+    async fn get_layer(&mut self) -> zlink::Result<Result<LayerResponse, KeystrokeError>>;
+
+    async fn set_layer(
+        &mut self,
+        layer: &str,
+    ) -> zlink::Result<Result<LayerResponse, KeystrokeError>>;
+
+    async fn get_status(&mut self) -> zlink::Result<Result<StatusResponse, KeystrokeError>>;
+
+    async fn report_devices(
+        &mut self,
+        client: &str,
+        devices: &[CaptureDevice],
+    ) -> zlink::Result<Result<(), KeystrokeError>>;
+
+    async fn set_dry_run(&mut self, enabled: bool) -> zlink::Result<Result<(), KeystrokeError>>;
+
+    async fn get_history(&mut self) -> zlink::Result<Result<HistoryResponse, KeystrokeError>>;
+
+    async fn get_command(
+        &mut self,
+        id: u64,
+        timeout_ms: Option<u64>,
+    ) -> zlink::Result<Result<CommandStatus, KeystrokeError>>;
+
+    /// Stream commands as they are triggered
+    #[zlink(more)]
+    async fn monitor(
+        &mut self,
+    ) -> zlink::Result<
+        impl futures_util::Stream<Item = zlink::Result<Result<MonitorEvent, KeystrokeError>>>,
+    >;
 }
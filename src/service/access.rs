@@ -0,0 +1,180 @@
+//! Authorization of varlink clients.
+//!
+//! The packaged socket only lets root and the `duckycap` group connect, and
+//! every call is further checked against the `[access]` allowlists using
+//! the caller's `SO_PEERCRED` credentials and `SO_PEERGROUPS` supplementary
+//! groups, both as of when it connected. Root and the user the service runs
+//! as are always allowed.
+//!
+//! The executable of a caller is read from `/proc/<pid>/exe` when a call is
+//! checked instead, so the caller may have exec'd since connecting. Its PID
+//! is pinned with the `SO_PEERPIDFD` pidfd of the connection: if the caller
+//! exited before its executable was read, the call is denied. Kernels older
+//! than 6.5 don't have it, and there a reused PID can't be detected.
+
+use super::config::{AccessConfig, AccessRule};
+use std::fmt;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+
+/// What a method lets its caller do
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Send keys and read the service state
+    Send,
+    /// Change the service state
+    Manage,
+}
+
+/// Credentials of the process on the other end of a connection
+pub struct Peer {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups
+    pub groups: Vec<u32>,
+    pub pid: i32,
+    /// Pidfd of the peer, `None` on kernels without `SO_PEERPIDFD`
+    pidfd: Option<OwnedFd>,
+}
+
+impl Peer {
+    /// Get the credentials of a socket's peer as of when it connected
+    pub fn of(socket: impl AsFd) -> io::Result<Self> {
+        let cred = rustix::net::sockopt::socket_peercred(&socket)?;
+        Ok(Self {
+            uid: cred.uid.as_raw(),
+            gid: cred.gid.as_raw(),
+            groups: peer_groups(&socket)?,
+            pid: cred.pid.as_raw_nonzero().get(),
+            pidfd: peer_pidfd(&socket)?,
+        })
+    }
+
+    /// Path of the executable the peer is running
+    ///
+    /// `None` if it can't be read, or if the peer exited in the meantime and
+    /// the path may be that of another process that got its PID.
+    fn exe(&self) -> Option<PathBuf> {
+        let exe = std::fs::read_link(format!("/proc/{}/exe", self.pid)).ok()?;
+        match &self.pidfd {
+            Some(pidfd) => is_alive(pidfd).then_some(exe),
+            None => Some(exe),
+        }
+    }
+}
+
+/// Get a pidfd of a socket's peer, `None` if the kernel doesn't support it
+fn peer_pidfd(socket: impl AsFd) -> io::Result<Option<OwnedFd>> {
+    let mut fd: libc::c_int = -1;
+    let mut len = libc::socklen_t::try_from(size_of::<libc::c_int>())
+        .map_err(|_| io::Error::other("invalid option size"))?;
+    // SAFETY: `fd` is valid for `len` bytes, and the kernel writes a new file
+    // descriptor to it on success.
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_fd().as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERPIDFD,
+            (&raw mut fd).cast(),
+            &raw mut len,
+        )
+    };
+    if result != 0 {
+        let error = io::Error::last_os_error();
+        return match error.raw_os_error() {
+            Some(libc::ENOPROTOOPT) => Ok(None),
+            _ => Err(error),
+        };
+    }
+    // SAFETY: the kernel returned a new file descriptor that nothing else owns
+    Ok(Some(unsafe { OwnedFd::from_raw_fd(fd) }))
+}
+
+/// Whether the process of a pidfd hasn't been reaped, so its PID can't have
+/// been reused
+fn is_alive(pidfd: &OwnedFd) -> bool {
+    // SAFETY: signal 0 only checks that the process exists, and no siginfo
+    // or flags are passed.
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            pidfd.as_raw_fd(),
+            0,
+            std::ptr::null::<libc::siginfo_t>(),
+            0,
+        )
+    };
+    result == 0
+}
+
+/// Get the supplementary groups of a socket's peer as of when it connected
+fn peer_groups(socket: impl AsFd) -> io::Result<Vec<u32>> {
+    const GID_SIZE: usize = size_of::<libc::gid_t>();
+
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut len = libc::socklen_t::try_from(groups.len() * GID_SIZE)
+            .map_err(|_| io::Error::other("too many groups"))?;
+        // SAFETY: the buffer is valid for `len` bytes, which the kernel
+        // updates to the number of bytes it wrote or, with ERANGE, needs.
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_fd().as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERGROUPS,
+                groups.as_mut_ptr().cast(),
+                &raw mut len,
+            )
+        };
+        let count = len as usize / GID_SIZE;
+        if result == 0 {
+            groups.truncate(count);
+            return Ok(groups);
+        }
+
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ERANGE) || count <= groups.len() {
+            return Err(error);
+        }
+        groups.resize(count, 0);
+    }
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid {} (uid {}, gid {})", self.pid, self.uid, self.gid)
+    }
+}
+
+impl AccessConfig {
+    /// Whether a peer may call methods needing `permission`
+    ///
+    /// Clients allowed to manage the service may also send keys. The user the
+    /// service runs as could as well stop it, e.g. in a per-user unit.
+    pub fn allows(&self, peer: &Peer, permission: Permission) -> bool {
+        if peer.uid == 0 || peer.uid == rustix::process::geteuid().as_raw() {
+            return true;
+        }
+
+        match permission {
+            Permission::Send => self.send.matches(peer) || self.manage.matches(peer),
+            Permission::Manage => self.manage.matches(peer),
+        }
+    }
+}
+
+impl AccessRule {
+    fn matches(&self, peer: &Peer) -> bool {
+        let in_group = self.gids.contains(&peer.gid)
+            || peer.groups.iter().any(|group| self.gids.contains(group));
+        if !self.uids.contains(&peer.uid) && !in_group {
+            return false;
+        }
+
+        self.executables.is_empty()
+            || peer
+                .exe()
+                .is_some_and(|exe| self.executables.contains(&exe))
+    }
+}
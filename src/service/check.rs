@@ -0,0 +1,356 @@
+//! Config file validation for `--check`.
+//!
+//! Goes beyond what loading the config needs: key names are checked against
+//! the key table, later mappings may not silently replace earlier ones, and
+//! the user and scripts commands refer to must exist. All problems are
+//! reported with the line of the mapping they were found in.
+
+use super::config::{Action, BASE_LAYER, CommandMapping, Config, Resolved, parse_key_combination};
+use super::executor::needs_runuser;
+use super::limits::Limiter;
+use super::session::Session;
+use super::units::Units;
+use crate::is_key_name;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use toml::Spanned;
+
+/// A problem found in the config file
+pub struct Diagnostic {
+    /// Line the problem was found on, if it can be pinned to one
+    pub line: Option<usize>,
+    pub message: String,
+    /// Only informational, e.g. because it depends on how the service is
+    /// run, so it doesn't fail the check
+    pub note: bool,
+}
+
+/// Formats diagnostics as `path:line: message`, or `path:line: note: message`
+/// for notes
+pub struct Report<'a> {
+    pub path: &'a Path,
+    pub diagnostic: &'a Diagnostic,
+}
+
+impl fmt::Display for Report<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:", self.path.display())?;
+        if let Some(line) = self.diagnostic.line {
+            write!(f, "{line}:")?;
+        }
+        if self.diagnostic.note {
+            f.write_str(" note:")?;
+        }
+        write!(f, " {}", self.diagnostic.message)
+    }
+}
+
+/// Positions of the parts of the config file diagnostics refer to
+#[derive(Deserialize)]
+struct Spans {
+    user: Option<Spanned<toml::Value>>,
+    #[serde(default)]
+    commands: Vec<Spanned<toml::Value>>,
+    #[serde(default)]
+    layers: HashMap<String, LayerSpans>,
+}
+
+#[derive(Deserialize)]
+struct LayerSpans {
+    #[serde(default)]
+    commands: Vec<Spanned<toml::Value>>,
+}
+
+/// Check a config file, returning all problems found
+pub fn check(path: &PathBuf) -> Vec<Diagnostic> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            return vec![Diagnostic {
+                line: None,
+                message: format!("failed to read config file: {e}"),
+                note: false,
+            }];
+        }
+    };
+    let line_of = |offset: usize| content[..offset].matches('\n').count() + 1;
+
+    let config: Config = match toml::from_str(&content) {
+        Ok(config) => config,
+        Err(e) => {
+            return vec![Diagnostic {
+                line: e.span().map(|span| line_of(span.start)),
+                message: e.message().to_string(),
+                note: false,
+            }];
+        }
+    };
+    // Spans are stricter than the config in places, e.g. about layer commands
+    let spans: Spans = match toml::from_str(&content) {
+        Ok(spans) => spans,
+        Err(e) => {
+            return vec![Diagnostic {
+                line: e.span().map(|span| line_of(span.start)),
+                message: e.message().to_string(),
+                note: false,
+            }];
+        }
+    };
+
+    let mut diagnostics = Vec::new();
+
+    diagnostics.extend(check_user(
+        &config.user,
+        spans.user.map(|user| line_of(user.span().start)),
+    ));
+
+    let mut layers: Vec<(&str, &[CommandMapping], Vec<usize>)> = vec![(
+        BASE_LAYER,
+        &config.commands,
+        spans
+            .commands
+            .iter()
+            .map(|c| line_of(c.span().start))
+            .collect(),
+    )];
+    let mut names: Vec<&String> = config.layers.keys().collect();
+    names.sort();
+    for name in names {
+        let lines = spans.layers.get(name).map_or_else(Vec::new, |layer| {
+            layer
+                .commands
+                .iter()
+                .map(|c| line_of(c.span().start))
+                .collect()
+        });
+        layers.push((name, &config.layers[name].commands, lines));
+    }
+
+    // Names have to be unique for logs and metrics to tell mappings apart
+    let mut ids: HashMap<String, usize> = HashMap::new();
+    for (name, commands, lines) in &layers {
+        for (index, cmd) in commands.iter().enumerate() {
+            let id = cmd.id(name, index);
+            if let Some(first) = ids.get(&id) {
+                diagnostics.push(Diagnostic {
+                    line: Some(lines[index]),
+                    message: format!("name '{id}' is already used on line {first}"),
+                    note: false,
+                });
+            } else {
+                ids.insert(id, lines[index]);
+            }
+        }
+        check_layer(name, commands, lines, config.hold_ms, &mut diagnostics);
+    }
+
+    // Checks across layers only make sense once every mapping resolves
+    if diagnostics.is_empty() {
+        diagnostics.extend(
+            check_settings(&config)
+                .into_iter()
+                .map(|message| Diagnostic {
+                    line: None,
+                    message,
+                    note: false,
+                }),
+        );
+    }
+
+    diagnostics.sort_by_key(|d| d.line);
+    diagnostics
+}
+
+/// Check what loading the config checks beyond single mappings, which
+/// can't be pinned to a line
+fn check_settings(config: &Config) -> Vec<String> {
+    [
+        config.build_layers().map(drop),
+        config.metrics.listen().map(drop),
+        config.metrics.textfile_interval().map(drop),
+        Limiter::from_config(&config.limits).map(drop),
+        Session::from_config(&config.session).map(drop),
+        Units::from_config(config).map(drop),
+    ]
+    .into_iter()
+    .filter_map(Result::err)
+    .collect()
+}
+
+/// Check the mappings of a single layer
+fn check_layer(
+    layer: &str,
+    commands: &[CommandMapping],
+    lines: &[usize],
+    hold_ms: u64,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    // Lines of the mappings for each key combination and tap count, and of
+    // each sequence, to find mappings that replace or shadow others
+    let mut combos: HashMap<(Vec<String>, u32), usize> = HashMap::new();
+    let mut sequences: Vec<(Vec<Vec<String>>, usize)> = Vec::new();
+
+    for (index, cmd) in commands.iter().enumerate() {
+        let line = lines[index];
+        let mut report = |message: String| {
+            diagnostics.push(Diagnostic {
+                line: Some(line),
+                message,
+                note: false,
+            });
+        };
+
+        for key in cmd.keys.iter().chain(cmd.sequence.iter().flatten()) {
+            for name in parse_key_combination(key) {
+                if !is_key_name(&name) {
+                    report(format!("unknown key '{name}' in '{key}'"));
+                }
+            }
+        }
+
+        let resolved = match cmd.resolve(cmd.id(layer, index), hold_ms) {
+            Ok(resolved) => resolved,
+            Err(message) => {
+                report(message);
+                continue;
+            }
+        };
+
+        let actions: Vec<&Action> = match &resolved {
+            Resolved::Binding(_, binding) => binding.actions().collect(),
+            Resolved::Taps(_, _, action) => vec![action],
+            Resolved::Sequence(sequence) => vec![&sequence.action],
+        };
+        for action in actions {
+            if let Action::Command(cmd) = action {
+                if let Err(message) = check_script(cmd) {
+                    report(message);
+                }
+            }
+        }
+
+        match resolved {
+            Resolved::Binding(keys, _) => {
+                if let Some(first) = combos.insert((keys.clone(), 1), line) {
+                    report(format!(
+                        "'{}' is already mapped on line {first}, which this mapping replaces",
+                        keys.join("+")
+                    ));
+                }
+            }
+            Resolved::Taps(keys, taps, _) => {
+                if let Some(first) = combos.insert((keys.clone(), taps), line) {
+                    report(format!(
+                        "'{}' with 'taps = {taps}' is already mapped on line {first}, which this mapping replaces",
+                        keys.join("+")
+                    ));
+                }
+            }
+            Resolved::Sequence(sequence) => sequences.push((sequence.steps, line)),
+        }
+    }
+
+    for (steps, line) in &sequences {
+        let describe = |steps: &[Vec<String>]| -> String {
+            steps
+                .iter()
+                .map(|s| s.join("+"))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+
+        // Sequences take precedence over key combinations
+        if let Some(first) = combos.get(&(steps[0].clone(), 1)) {
+            diagnostics.push(Diagnostic {
+                line: Some(*first),
+                message: format!(
+                    "'{}' never fires, it starts the sequence '{}' on line {line}",
+                    steps[0].join("+"),
+                    describe(steps)
+                ),
+                note: false,
+            });
+        }
+
+        // A sequence completes before a longer one it starts can
+        for (other, other_line) in &sequences {
+            if other.len() > steps.len() && other.starts_with(steps) {
+                diagnostics.push(Diagnostic {
+                    line: Some(*other_line),
+                    message: format!(
+                        "sequence '{}' never fires, '{}' on line {line} completes first",
+                        describe(other),
+                        describe(steps)
+                    ),
+                    note: false,
+                });
+            } else if other == steps && other_line > line {
+                diagnostics.push(Diagnostic {
+                    line: Some(*other_line),
+                    message: format!(
+                        "sequence '{}' is already mapped on line {line}",
+                        describe(steps)
+                    ),
+                    note: false,
+                });
+            }
+        }
+    }
+}
+
+/// Check that the user commands run as exists
+///
+/// Whether the service can run commands as it depends on who the service runs
+/// as, so that is only noted for the user running the check.
+fn check_user(user: &str, line: Option<usize>) -> Option<Diagnostic> {
+    let error = |message| {
+        Some(Diagnostic {
+            line,
+            message,
+            note: false,
+        })
+    };
+    let status = match Command::new("getent")
+        .args(["passwd", user])
+        .stdout(Stdio::null())
+        .status()
+    {
+        Ok(status) => status,
+        Err(e) => return error(format!("failed to look up user '{user}': {e}")),
+    };
+    if !status.success() {
+        return error(format!("user '{user}' does not exist"));
+    }
+
+    needs_runuser(user).err().map(|e| Diagnostic {
+        line,
+        message: format!("{e}; the service has to run as root or as '{user}'"),
+        note: true,
+    })
+}
+
+/// Check that the script of a command with an absolute path is executable
+fn check_script(cmd: &str) -> Result<(), String> {
+    if !cmd.starts_with('/') {
+        return Ok(());
+    }
+
+    let parts = shlex::split(cmd).ok_or_else(|| format!("command '{cmd}' has invalid quoting"))?;
+    let Some(script) = parts.first() else {
+        return Ok(());
+    };
+
+    let metadata =
+        std::fs::metadata(script).map_err(|e| format!("script '{script}' is not usable: {e}"))?;
+    if !metadata.is_file() {
+        return Err(format!("script '{script}' is not a file"));
+    }
+    if metadata.permissions().mode() & 0o111 == 0 {
+        return Err(format!("script '{script}' is not executable"));
+    }
+    Ok(())
+}
@@ -0,0 +1,981 @@
+//! TOML configuration for the varlink service.
+//!
+//! The top-level `[[commands]]` table forms the base layer. Additional
+//! layers are declared as `[layers.<name>]` tables with their own
+//! `[[layers.<name>.commands]]` bindings.
+
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// Name of the layer built from the top-level `[[commands]]` table
+pub const BASE_LAYER: &str = "base";
+
+/// Default time keys must be held before a `hold` action fires
+const DEFAULT_HOLD_MS: u64 = 300;
+
+/// Default time to wait for another tap of a multi-tap binding
+const DEFAULT_TAP_WINDOW_MS: u64 = 250;
+
+/// Default time to wait for the next step of a key sequence
+const DEFAULT_SEQUENCE_TIMEOUT_MS: u64 = 1000;
+
+/// Default time to ignore repeated presses of the same key combination
+const DEFAULT_DEBOUNCE_MS: u64 = 50;
+
+/// Default time without keystrokes before the service exits
+const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 300;
+
+/// Default interval for writing the metrics textfile
+const DEFAULT_METRICS_TEXTFILE_INTERVAL_SECS: u64 = 15;
+
+/// Default commands started per minute across all bindings
+const DEFAULT_COMMANDS_PER_MINUTE: u32 = 1800;
+
+/// Default commands that may start at once across all bindings
+const DEFAULT_BURST: u32 = 60;
+
+/// Default commands that may start at once for each binding
+const DEFAULT_BINDING_BURST: u32 = 10;
+
+/// Default number of commands that may run at the same time
+const DEFAULT_MAX_RUNNING: usize = 32;
+
+/// Default variables imported from the graphical session of the user
+const DEFAULT_SESSION_VARIABLES: &[&str] = &[
+    "WAYLAND_DISPLAY",
+    "DISPLAY",
+    "XAUTHORITY",
+    "DBUS_SESSION_BUS_ADDRESS",
+    "XDG_RUNTIME_DIR",
+    "XDG_SESSION_TYPE",
+    "XDG_CURRENT_DESKTOP",
+];
+
+// ============================================================================
+// TOML Configuration
+// ============================================================================
+
+/// Root configuration structure
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    /// User to run commands as
+    pub user: String,
+    /// Time in milliseconds keys must be held before a `hold` action fires
+    #[serde(default = "default_hold_ms")]
+    pub hold_ms: u64,
+    /// Time in milliseconds to wait for another tap of a multi-tap binding
+    #[serde(default = "default_tap_window_ms")]
+    pub tap_window_ms: u64,
+    /// Time in milliseconds to wait for the next step of a key sequence
+    #[serde(default = "default_sequence_timeout_ms")]
+    pub sequence_timeout_ms: u64,
+    /// Time in milliseconds to ignore repeated presses of the same key combination
+    #[serde(default = "default_debounce_ms")]
+    pub debounce_ms: u64,
+    /// Time in seconds without keystrokes before the service exits (0 disables it)
+    #[serde(default = "default_idle_timeout_secs")]
+    pub idle_timeout_secs: u64,
+    /// List of command mappings (the base layer)
+    #[serde(default)]
+    pub commands: Vec<CommandMapping>,
+    /// Named layers of additional command mappings
+    #[serde(default)]
+    pub layers: HashMap<String, LayerConfig>,
+    /// Where to export metrics, if anywhere
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    /// Who may call the service, besides root
+    #[serde(default)]
+    pub access: AccessConfig,
+    /// How fast and how many commands may start
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Environment of commands
+    #[serde(default)]
+    pub session: SessionConfig,
+    /// Running commands as transient systemd units
+    #[serde(default)]
+    pub units: UnitsConfig,
+}
+
+/// Transient units in the user's systemd manager that commands run as
+#[derive(Debug, Default, Deserialize)]
+pub struct UnitsConfig {
+    /// Run commands as units instead of as children of the service
+    #[serde(default)]
+    pub enabled: bool,
+    /// Properties of every unit, e.g. `MemoryMax`
+    #[serde(default)]
+    pub properties: BTreeMap<String, String>,
+}
+
+/// Environment commands run in, so desktop-facing commands reach the
+/// user's graphical session
+#[derive(Debug, Deserialize)]
+pub struct SessionConfig {
+    /// Import variables from the environment of the user's systemd manager
+    #[serde(default = "default_import")]
+    pub import: bool,
+    /// Variables to import
+    #[serde(default = "default_session_variables")]
+    pub variables: Vec<String>,
+    /// Variables to set, overriding imported ones
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            import: true,
+            variables: default_session_variables(),
+            env: BTreeMap::new(),
+        }
+    }
+}
+
+/// Limits on starting commands
+#[derive(Debug, Deserialize)]
+pub struct LimitsConfig {
+    /// Commands started per minute across all bindings (0 disables it)
+    #[serde(default = "default_commands_per_minute")]
+    pub commands_per_minute: u32,
+    /// Commands that may start at once across all bindings
+    #[serde(default = "default_burst")]
+    pub burst: u32,
+    /// Commands started per minute by each binding (0 disables it)
+    #[serde(default)]
+    pub binding_commands_per_minute: u32,
+    /// Commands that may start at once for each binding
+    #[serde(default = "default_binding_burst")]
+    pub binding_burst: u32,
+    /// Commands that may run at the same time (0 disables it)
+    #[serde(default = "default_max_running")]
+    pub max_running: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            commands_per_minute: DEFAULT_COMMANDS_PER_MINUTE,
+            burst: DEFAULT_BURST,
+            binding_commands_per_minute: 0,
+            binding_burst: DEFAULT_BINDING_BURST,
+            max_running: DEFAULT_MAX_RUNNING,
+        }
+    }
+}
+
+/// Allowlists of varlink clients, matched against their peer credentials
+#[derive(Debug, Default, Deserialize)]
+pub struct AccessConfig {
+    /// Clients that may send keys and read the service state
+    #[serde(default)]
+    pub send: AccessRule,
+    /// Clients that may also change the service state, e.g. switch layers
+    #[serde(default)]
+    pub manage: AccessRule,
+}
+
+/// Clients matching any of the UIDs or primary or supplementary GIDs, and
+/// running one of the executables if any are given
+#[derive(Debug, Default, Deserialize)]
+pub struct AccessRule {
+    #[serde(default)]
+    pub uids: Vec<u32>,
+    #[serde(default)]
+    pub gids: Vec<u32>,
+    #[serde(default)]
+    pub executables: Vec<PathBuf>,
+}
+
+/// Metrics exporter settings
+#[derive(Debug, Deserialize)]
+pub struct MetricsConfig {
+    /// Serve metrics over HTTP on a Unix socket path or a loopback `address:port`
+    pub listen: Option<String>,
+    /// Path of a `node_exporter` textfile collector file to write metrics to
+    pub textfile: Option<PathBuf>,
+    /// Time in seconds between writes of the textfile
+    #[serde(default = "default_metrics_textfile_interval_secs")]
+    pub textfile_interval_secs: u64,
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            listen: None,
+            textfile: None,
+            textfile_interval_secs: DEFAULT_METRICS_TEXTFILE_INTERVAL_SECS,
+        }
+    }
+}
+
+/// Where the metrics endpoint listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MetricsListen {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl fmt::Display for MetricsListen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "'{}'", path.display()),
+            Self::Tcp(addr) => write!(f, "http://{addr}/metrics"),
+        }
+    }
+}
+
+impl MetricsConfig {
+    /// Parse the `listen` setting
+    ///
+    /// Absolute paths are Unix sockets, anything else must be a loopback
+    /// `address:port`, as the metrics aren't meant to leave the machine.
+    pub fn listen(&self) -> Result<Option<MetricsListen>, String> {
+        let Some(listen) = &self.listen else {
+            return Ok(None);
+        };
+
+        if listen.starts_with('/') {
+            return Ok(Some(MetricsListen::Unix(PathBuf::from(listen))));
+        }
+
+        let addr: SocketAddr = listen
+            .parse()
+            .map_err(|e| format!("metrics 'listen = \"{listen}\"' is invalid: {e}"))?;
+        if !addr.ip().is_loopback() {
+            return Err(format!(
+                "metrics 'listen = \"{listen}\"' must be a loopback address or a Unix socket path"
+            ));
+        }
+
+        Ok(Some(MetricsListen::Tcp(addr)))
+    }
+
+    /// Interval for writing the textfile, if one is configured
+    pub fn textfile_interval(&self) -> Result<Option<Duration>, String> {
+        if self.textfile.is_none() {
+            return Ok(None);
+        }
+        if self.textfile_interval_secs == 0 {
+            return Err("metrics 'textfile_interval_secs' must be greater than 0".to_string());
+        }
+        Ok(Some(Duration::from_secs(self.textfile_interval_secs)))
+    }
+}
+
+/// A named set of command mappings
+#[derive(Debug, Deserialize)]
+pub struct LayerConfig {
+    /// Fall back to the base layer for keys without a mapping in this layer
+    #[serde(default = "default_fallthrough")]
+    pub fallthrough: bool,
+    /// List of command mappings
+    #[serde(default)]
+    pub commands: Vec<CommandMapping>,
+}
+
+fn default_fallthrough() -> bool {
+    true
+}
+
+fn default_hold_ms() -> u64 {
+    DEFAULT_HOLD_MS
+}
+
+fn default_tap_window_ms() -> u64 {
+    DEFAULT_TAP_WINDOW_MS
+}
+
+fn default_sequence_timeout_ms() -> u64 {
+    DEFAULT_SEQUENCE_TIMEOUT_MS
+}
+
+fn default_debounce_ms() -> u64 {
+    DEFAULT_DEBOUNCE_MS
+}
+
+fn default_idle_timeout_secs() -> u64 {
+    DEFAULT_IDLE_TIMEOUT_SECS
+}
+
+fn default_metrics_textfile_interval_secs() -> u64 {
+    DEFAULT_METRICS_TEXTFILE_INTERVAL_SECS
+}
+
+fn default_commands_per_minute() -> u32 {
+    DEFAULT_COMMANDS_PER_MINUTE
+}
+
+fn default_burst() -> u32 {
+    DEFAULT_BURST
+}
+
+fn default_binding_burst() -> u32 {
+    DEFAULT_BINDING_BURST
+}
+
+fn default_max_running() -> usize {
+    DEFAULT_MAX_RUNNING
+}
+
+fn default_import() -> bool {
+    true
+}
+
+fn default_session_variables() -> Vec<String> {
+    DEFAULT_SESSION_VARIABLES
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn default_taps() -> u32 {
+    1
+}
+
+/// A single key combination to action mapping
+#[derive(Debug, Deserialize)]
+pub struct CommandMapping {
+    /// Name identifying the mapping in logs, defaults to `<layer>#<position>`
+    pub name: Option<String>,
+    /// Key combination string (e.g., "meta+f1", "a", "ctrl+shift+b")
+    pub keys: Option<String>,
+    /// Key combinations pressed one after another (e.g., `["f1", "a", "s"]`),
+    /// used instead of `keys`
+    pub sequence: Option<Vec<String>>,
+    /// Action performed as soon as the keys are pressed
+    #[serde(flatten)]
+    pub action: ActionConfig,
+    /// Action performed when the keys are released before the hold threshold
+    pub tap: Option<ActionConfig>,
+    /// Action performed once the keys are held past the hold threshold
+    pub hold: Option<ActionConfig>,
+    /// Hold threshold in milliseconds, overriding the global `hold_ms`
+    pub hold_ms: Option<u64>,
+    /// Number of taps within the tap window that trigger this mapping
+    #[serde(default = "default_taps")]
+    pub taps: u32,
+    /// Action performed when the keys are pressed, paired with `on_release`
+    pub on_press: Option<ActionConfig>,
+    /// Action performed when any of the keys is released
+    pub on_release: Option<ActionConfig>,
+    /// How the press action repeats while the keys are held
+    #[serde(default)]
+    pub repeat: Repeat,
+    /// Debounce window in milliseconds, overriding the global `debounce_ms`
+    pub debounce_ms: Option<u64>,
+    /// Properties of the units the mapping's commands run as, overriding
+    /// those of `[units]`
+    #[serde(default)]
+    pub unit: BTreeMap<String, String>,
+}
+
+/// An action, written as either `cmd = "..."` or `layer = { ... }`
+#[derive(Debug, Default, Deserialize)]
+pub struct ActionConfig {
+    /// Command to execute - if it starts with '/' it's treated as a script path,
+    /// otherwise it's run as a shell command
+    pub cmd: Option<String>,
+    /// Layer change to perform instead of running a command
+    pub layer: Option<LayerAction>,
+}
+
+/// A change to the active layer
+///
+/// Written in the config as an inline table, e.g. `layer = { toggle = "obs" }`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayerAction {
+    /// Activate the layer while the key combination is held
+    Momentary(String),
+    /// Activate the layer, or return to the previous layer if it is already active
+    Toggle(String),
+    /// Activate the layer for the next action only
+    OneShot(String),
+    /// Make the layer the active layer
+    Switch(String),
+}
+
+impl LayerAction {
+    /// Name of the layer this action targets
+    pub fn target(&self) -> &str {
+        match self {
+            Self::Momentary(layer)
+            | Self::Toggle(layer)
+            | Self::OneShot(layer)
+            | Self::Switch(layer) => layer,
+        }
+    }
+}
+
+impl fmt::Display for LayerAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            Self::Momentary(_) => "momentary",
+            Self::Toggle(_) => "toggle",
+            Self::OneShot(_) => "one-shot",
+            Self::Switch(_) => "switch",
+        };
+        write!(f, "{kind} layer '{}'", self.target())
+    }
+}
+
+/// How a binding's press action repeats while its keys are held
+///
+/// Written in the config as `repeat = "kernel"` or `repeat = { interval_ms = 100 }`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Repeat {
+    /// Fire once per press
+    #[default]
+    Ignore,
+    /// Fire again on every auto-repeat event of the keyboard
+    Kernel,
+    /// Fire again every this many milliseconds
+    IntervalMs(u64),
+}
+
+impl fmt::Display for Repeat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ignore => f.write_str("no repeat"),
+            Self::Kernel => f.write_str("kernel repeat"),
+            Self::IntervalMs(ms) => write!(f, "repeat every {ms}ms"),
+        }
+    }
+}
+
+/// What a key combination does once resolved
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Run a command as the configured user
+    Command(String),
+    /// Change the active layer
+    Layer(LayerAction),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Command(cmd) => f.write_str(cmd),
+            Self::Layer(action) => action.fmt(f),
+        }
+    }
+}
+
+impl ActionConfig {
+    /// Resolve into an action, or `None` if neither `cmd` nor `layer` is set
+    fn resolve(&self) -> Result<Option<Action>, String> {
+        match (&self.cmd, &self.layer) {
+            (Some(cmd), None) => Ok(Some(Action::Command(cmd.clone()))),
+            (None, Some(layer)) => Ok(Some(Action::Layer(layer.clone()))),
+            (Some(_), Some(_)) => Err("has both 'cmd' and 'layer'".to_string()),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Resolve a nested action table such as `tap = { ... }`, which must not be empty
+    fn resolve_nested(&self, name: &str) -> Result<Action, String> {
+        self.resolve()
+            .map_err(|e| format!("'{name}' {e}"))?
+            .ok_or_else(|| format!("'{name}' needs either 'cmd' or 'layer'"))
+    }
+}
+
+/// A key combination's actions once resolved
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Binding {
+    /// Identifies the binding in logs
+    pub id: String,
+    /// Action performed as soon as the keys are pressed
+    pub press: Option<Action>,
+    /// Action performed when the keys are released before `hold_after`
+    pub tap: Option<Action>,
+    /// Action performed once the keys are held for `hold_after`
+    pub hold: Option<Action>,
+    /// Action performed when any of the keys is released
+    pub release: Option<Action>,
+    /// How `press` repeats while the keys are held
+    pub repeat: Repeat,
+    /// How long the keys must be held before `hold` fires
+    pub hold_after: Duration,
+    /// Debounce window overriding the global one
+    pub debounce: Option<Duration>,
+    /// Actions performed when the keys are tapped this many times within the
+    /// tap window. When set, `press` and `tap` wait for the window to close.
+    pub taps: BTreeMap<u32, Action>,
+}
+
+impl Binding {
+    /// Iterate over every action of this binding
+    pub fn actions(&self) -> impl Iterator<Item = &Action> {
+        [&self.press, &self.tap, &self.hold, &self.release]
+            .into_iter()
+            .flatten()
+            .chain(self.taps.values())
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(action) = &self.press {
+            match self.repeat {
+                Repeat::Ignore => parts.push(action.to_string()),
+                repeat => parts.push(format!("{action} ({repeat})")),
+            }
+        }
+        if let Some(action) = &self.tap {
+            parts.push(format!("tap: {action}"));
+        }
+        if let Some(action) = &self.hold {
+            parts.push(format!("hold {:?}: {action}", self.hold_after));
+        }
+        if let Some(action) = &self.release {
+            parts.push(format!("release: {action}"));
+        }
+        for (taps, action) in &self.taps {
+            parts.push(format!("x{taps}: {action}"));
+        }
+        f.write_str(&parts.join(", "))
+    }
+}
+
+/// A single command mapping once resolved
+pub enum Resolved {
+    /// Binding of a key combination
+    Binding(Vec<String>, Binding),
+    /// Action for a number of taps of a key combination
+    Taps(Vec<String>, u32, Action),
+    Sequence(Sequence),
+}
+
+/// An ordered sequence of key combinations and the action it triggers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sequence {
+    /// Identifies the sequence in logs
+    pub id: String,
+    pub steps: Vec<Vec<String>>,
+    pub action: Action,
+}
+
+impl CommandMapping {
+    /// ID of the mapping at `index` in a layer's commands
+    pub fn id(&self, layer: &str, index: usize) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("{layer}#{}", index + 1))
+    }
+
+    /// Resolve the mapping on its own, before it's merged into a layer
+    pub fn resolve(&self, id: String, hold_ms: u64) -> Result<Resolved, String> {
+        let keys = match (&self.keys, &self.sequence) {
+            (_, Some(steps)) => return Ok(Resolved::Sequence(self.sequence(id, steps)?)),
+            (Some(keys), None) => parse_key_combination(keys),
+            (None, None) => return Err("mapping needs either 'keys' or 'sequence'".to_string()),
+        };
+
+        match self.taps {
+            0 => Err(format!("mapping for '{}' has 'taps = 0'", self.describe())),
+            1 => Ok(Resolved::Binding(keys, self.binding(id, hold_ms)?)),
+            taps => Ok(Resolved::Taps(keys, taps, self.multi_tap_action()?)),
+        }
+    }
+
+    /// Describe the mapping's keys for error messages
+    fn describe(&self) -> String {
+        match (&self.keys, &self.sequence) {
+            (Some(keys), _) => keys.clone(),
+            (None, Some(sequence)) => sequence.join(" "),
+            (None, None) => "<no keys>".to_string(),
+        }
+    }
+
+    /// Resolve the mapping into a binding
+    fn binding(&self, id: String, default_hold_ms: u64) -> Result<Binding, String> {
+        let err = |e: String| format!("mapping for '{}' {e}", self.describe());
+
+        let press = match (self.action.resolve().map_err(err)?, &self.on_press) {
+            (None, None) => None,
+            (Some(action), None) => Some(action),
+            (None, Some(on_press)) => Some(on_press.resolve_nested("on_press").map_err(err)?),
+            (Some(_), Some(_)) => {
+                return Err(err(
+                    "cannot combine 'cmd'/'layer' with 'on_press'".to_string()
+                ));
+            }
+        };
+        let tap = self
+            .tap
+            .as_ref()
+            .map(|a| a.resolve_nested("tap"))
+            .transpose()
+            .map_err(err)?;
+        let hold = self
+            .hold
+            .as_ref()
+            .map(|a| a.resolve_nested("hold"))
+            .transpose()
+            .map_err(err)?;
+        let release = self
+            .on_release
+            .as_ref()
+            .map(|a| a.resolve_nested("on_release"))
+            .transpose()
+            .map_err(err)?;
+
+        if press.is_some() && tap.is_some() {
+            return Err(err(
+                "cannot combine 'cmd'/'layer' with 'tap'; use 'tap' for short presses".to_string(),
+            ));
+        }
+        if self.repeat != Repeat::Ignore && (press.is_none() || tap.is_some() || hold.is_some()) {
+            return Err(err(
+                "needs 'cmd', 'layer' or 'on_press' to repeat, and cannot combine 'repeat' with 'tap' or 'hold'"
+                    .to_string(),
+            ));
+        }
+        if self.repeat == Repeat::IntervalMs(0) {
+            return Err(err("has a repeat interval of 0ms".to_string()));
+        }
+        if press.is_none() && tap.is_none() && hold.is_none() && release.is_none() {
+            return Err(err(
+                "needs 'cmd', 'layer', 'tap', 'hold' or 'on_release'".to_string()
+            ));
+        }
+
+        Ok(Binding {
+            id,
+            press,
+            tap,
+            hold,
+            release,
+            repeat: self.repeat,
+            hold_after: Duration::from_millis(self.hold_ms.unwrap_or(default_hold_ms)),
+            debounce: self.debounce_ms.map(Duration::from_millis),
+            taps: BTreeMap::new(),
+        })
+    }
+
+    /// Resolve a mapping with `taps` greater than one into its action
+    fn multi_tap_action(&self) -> Result<Action, String> {
+        let err = |e: &str| {
+            format!(
+                "mapping for '{}' with 'taps = {}' {e}",
+                self.describe(),
+                self.taps
+            )
+        };
+
+        if self.tap.is_some()
+            || self.hold.is_some()
+            || self.hold_ms.is_some()
+            || self.on_press.is_some()
+            || self.on_release.is_some()
+            || self.repeat != Repeat::Ignore
+            || self.debounce_ms.is_some()
+            || !self.unit.is_empty()
+        {
+            return Err(err(
+                "cannot use 'tap', 'hold', 'hold_ms', 'on_press', 'on_release', 'repeat', 'debounce_ms' or 'unit'; set them on the single-tap mapping",
+            ));
+        }
+
+        self.action
+            .resolve()
+            .map_err(|e| err(&e))?
+            .ok_or_else(|| err("needs either 'cmd' or 'layer'"))
+    }
+
+    /// Resolve a mapping with a `sequence` into a sequence binding
+    fn sequence(&self, id: String, steps: &[String]) -> Result<Sequence, String> {
+        let err = |e: &str| format!("mapping for sequence '{}' {e}", self.describe());
+
+        if self.keys.is_some() {
+            return Err(err("cannot have both 'keys' and 'sequence'"));
+        }
+        if steps.len() < 2 {
+            return Err(err(
+                "needs at least two steps; use 'keys' for a single step",
+            ));
+        }
+        if self.tap.is_some()
+            || self.hold.is_some()
+            || self.hold_ms.is_some()
+            || self.taps != 1
+            || self.on_press.is_some()
+            || self.on_release.is_some()
+            || self.repeat != Repeat::Ignore
+        {
+            return Err(err(
+                "cannot use 'tap', 'hold', 'hold_ms', 'taps', 'on_press', 'on_release' or 'repeat'",
+            ));
+        }
+
+        let action = self
+            .action
+            .resolve()
+            .map_err(|e| err(&e))?
+            .ok_or_else(|| err("needs either 'cmd' or 'layer'"))?;
+
+        Ok(Sequence {
+            id,
+            steps: steps.iter().map(|s| parse_key_combination(s)).collect(),
+            action,
+        })
+    }
+}
+
+/// Timing settings that apply to all bindings
+#[derive(Debug, Clone, Copy)]
+pub struct Timing {
+    /// How long to wait for another tap of a multi-tap binding
+    pub tap_window: Duration,
+    /// How long to wait for the next step of a key sequence
+    pub sequence_timeout: Duration,
+    /// How long to ignore repeated presses of the same key combination
+    pub debounce: Duration,
+}
+
+impl Config {
+    /// Load configuration from a TOML file
+    pub fn load(path: &PathBuf) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read config file '{}': {e}", path.display()))?;
+
+        toml::from_str(&content)
+            .map_err(|e| format!("Failed to parse config file '{}': {e}", path.display()))
+    }
+
+    /// Config without any mappings, used when the config file can't be loaded
+    pub fn unloaded() -> Self {
+        Self {
+            user: String::new(),
+            hold_ms: DEFAULT_HOLD_MS,
+            tap_window_ms: DEFAULT_TAP_WINDOW_MS,
+            sequence_timeout_ms: DEFAULT_SEQUENCE_TIMEOUT_MS,
+            debounce_ms: DEFAULT_DEBOUNCE_MS,
+            idle_timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+            commands: Vec::new(),
+            layers: HashMap::new(),
+            metrics: MetricsConfig::default(),
+            access: AccessConfig::default(),
+            limits: LimitsConfig::default(),
+            session: SessionConfig::default(),
+            units: UnitsConfig::default(),
+        }
+    }
+
+    /// Global timing settings
+    pub fn timing(&self) -> Timing {
+        Timing {
+            tap_window: Duration::from_millis(self.tap_window_ms),
+            sequence_timeout: Duration::from_millis(self.sequence_timeout_ms),
+            debounce: Duration::from_millis(self.debounce_ms),
+        }
+    }
+
+    /// Time without keystrokes before the service exits, if enabled
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+
+    /// Convert the base layer and all named layers into lookup tables
+    pub fn build_layers(&self) -> Result<Layers, String> {
+        if self.layers.contains_key(BASE_LAYER) {
+            return Err(format!(
+                "'{BASE_LAYER}' is reserved for the top-level commands"
+            ));
+        }
+
+        let mut layers = HashMap::new();
+        layers.insert(
+            BASE_LAYER.to_string(),
+            build_layer(BASE_LAYER, &self.commands, false, self.hold_ms)?,
+        );
+
+        for (name, layer) in &self.layers {
+            layers.insert(
+                name.clone(),
+                build_layer(name, &layer.commands, layer.fallthrough, self.hold_ms)
+                    .map_err(|e| format!("layer '{name}': {e}"))?,
+            );
+        }
+
+        // Every layer action must point at a layer that exists
+        for layer in layers.values() {
+            let actions = layer
+                .bindings
+                .values()
+                .flat_map(Binding::actions)
+                .chain(layer.sequences.iter().map(|s| &s.action));
+            for action in actions {
+                if let Action::Layer(action) = action {
+                    if !layers.contains_key(action.target()) {
+                        return Err(format!("unknown layer '{}'", action.target()));
+                    }
+                }
+            }
+        }
+
+        // A tap window within the debounce window could never see a second tap
+        let timing = self.timing();
+        for layer in layers.values() {
+            for (keys, binding) in &layer.bindings {
+                let debounce = binding.debounce.unwrap_or(timing.debounce);
+                if !binding.taps.is_empty() && timing.tap_window <= debounce {
+                    return Err(format!(
+                        "mapping for '{}': tap_window_ms must be longer than its {debounce:?} debounce",
+                        keys.join("+")
+                    ));
+                }
+            }
+        }
+
+        Ok(Layers { layers })
+    }
+}
+
+/// Convert command mappings to a layer with a `HashMap` for efficient lookup
+///
+/// Mappings with `taps` greater than one are merged into the binding of the
+/// same key combination, which takes the single-tap mapping's ID if there is one.
+fn build_layer(
+    name: &str,
+    commands: &[CommandMapping],
+    fallthrough: bool,
+    hold_ms: u64,
+) -> Result<Layer, String> {
+    let mut bindings: HashMap<Vec<String>, Binding> = HashMap::new();
+    let mut sequences = Vec::new();
+
+    for (index, cmd) in commands.iter().enumerate() {
+        match cmd.resolve(cmd.id(name, index), hold_ms)? {
+            Resolved::Sequence(sequence) => sequences.push(sequence),
+            Resolved::Binding(keys, binding) => {
+                let entry = bindings.entry(keys).or_default();
+                let taps = std::mem::take(&mut entry.taps);
+                *entry = Binding { taps, ..binding };
+            }
+            Resolved::Taps(keys, taps, action) => {
+                let entry = bindings.entry(keys).or_default();
+                if entry.id.is_empty() {
+                    entry.id = cmd.id(name, index);
+                }
+                entry.taps.insert(taps, action);
+            }
+        }
+    }
+
+    // A release or repeat can't be paired with a press that waits for the tap window
+    for (keys, binding) in &bindings {
+        if (binding.release.is_some() || binding.repeat != Repeat::Ignore)
+            && !binding.taps.is_empty()
+        {
+            return Err(format!(
+                "mapping for '{}' cannot combine 'on_release' or 'repeat' with multi-tap mappings",
+                keys.join("+")
+            ));
+        }
+    }
+
+    Ok(Layer {
+        fallthrough,
+        bindings,
+        sequences,
+    })
+}
+
+// ============================================================================
+// Key Combination Parsing
+// ============================================================================
+
+/// Parse a key combination string into a normalized vector of keys
+pub fn parse_key_combination(input: &str) -> Vec<String> {
+    let mut keys: Vec<String> = input.split('+').map(|s| s.trim().to_lowercase()).collect();
+    keys.sort();
+    keys
+}
+
+// ============================================================================
+// Layer Lookup
+// ============================================================================
+
+/// Bindings of a single layer
+#[derive(Debug)]
+pub struct Layer {
+    /// Fall back to the base layer for keys without a binding here
+    pub fallthrough: bool,
+    pub bindings: HashMap<Vec<String>, Binding>,
+    pub sequences: Vec<Sequence>,
+}
+
+/// All layers, keyed by name
+#[derive(Debug)]
+pub struct Layers {
+    layers: HashMap<String, Layer>,
+}
+
+impl Layers {
+    /// Check whether a layer with this name exists
+    pub fn contains(&self, name: &str) -> bool {
+        self.layers.contains_key(name)
+    }
+
+    /// Sorted names of all layers
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.layers.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Iterate over all layers and their names
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Layer)> {
+        self.layers.iter()
+    }
+
+    /// Total number of bindings and sequences across all layers
+    pub fn binding_count(&self) -> usize {
+        self.layers
+            .values()
+            .map(|l| l.bindings.len() + l.sequences.len())
+            .sum()
+    }
+
+    /// Sequences usable in a layer, including the base layer's when the layer
+    /// falls through to it
+    pub fn sequences(&self, layer: &str) -> impl Iterator<Item = &Sequence> {
+        let layer = self.layers.get(layer);
+        let base = layer
+            .filter(|l| l.fallthrough)
+            .and_then(|_| self.layers.get(BASE_LAYER));
+
+        layer
+            .into_iter()
+            .chain(base)
+            .flat_map(|l| l.sequences.iter())
+    }
+
+    /// Look up the binding for a key combination in a layer, falling back to
+    /// the base layer when the layer allows it
+    pub fn lookup(&self, layer: &str, keys: &[String]) -> Option<&Binding> {
+        let layer = self.layers.get(layer)?;
+        layer.bindings.get(keys).or_else(|| {
+            if layer.fallthrough {
+                self.layers.get(BASE_LAYER)?.bindings.get(keys)
+            } else {
+                None
+            }
+        })
+    }
+}
@@ -0,0 +1,842 @@
+//! Key event dispatcher.
+//!
+//! Tracks held key combinations and resolves them to actions in the active
+//! layer. The dispatcher never runs commands or sleeps itself: it returns
+//! [`Effect`]s for the service to carry out, and is told about expired
+//! timers through [`Dispatcher::expire`].
+
+use super::config::{Action, Binding, Layers, Repeat, Timing};
+use super::layers::LayerState;
+use crate::log_keys;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Something the service has to do on behalf of the dispatcher
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Effect {
+    /// Run a command triggered by a key combination
+    Run {
+        cmd: String,
+        keys: Vec<String>,
+        /// ID of the binding the command belongs to
+        binding: String,
+    },
+    /// Call [`Dispatcher::expire`] with the timer once the delay has passed
+    Schedule { timer: Timer, after: Duration },
+}
+
+/// Outcome of a pressed key combination
+#[derive(Debug, Default)]
+pub struct Press {
+    pub effects: Vec<Effect>,
+    /// Whether the keys matched a binding or a step of a key sequence
+    pub matched: bool,
+}
+
+/// A timer scheduled by the dispatcher
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    /// Hold threshold of the held key combination with this ID
+    Hold(u64),
+    /// Multi-tap window of the tap sequence with this ID
+    TapWindow(u64),
+    /// Timeout for the next step of the key sequence progress with this ID
+    Sequence(u64),
+    /// Next repeat of the held key combination with this ID
+    Repeat(u64),
+}
+
+/// A held key combination whose binding has a tap, hold, release or repeat action
+#[derive(Debug)]
+struct Held {
+    id: u64,
+    binding: Binding,
+    /// Set once the hold action fired, which suppresses the tap action
+    hold_fired: bool,
+    /// Whether releasing the keys performs the tap action. Multi-tap bindings
+    /// leave this unset until their tap window has closed.
+    tap_on_release: bool,
+}
+
+/// Taps of a multi-tap binding counted within its tap window
+#[derive(Debug)]
+struct TapSequence {
+    id: u64,
+    count: u32,
+    binding: Binding,
+}
+
+/// Steps of a key sequence matched so far
+#[derive(Debug)]
+struct SequenceProgress {
+    id: u64,
+    /// Layer the sequence was started in
+    layer: String,
+    steps: Vec<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub struct Dispatcher {
+    layers: Layers,
+    layer_state: LayerState,
+    timing: Timing,
+    /// Held key combinations with pending tap/hold/release/repeat actions
+    held: HashMap<Vec<String>, Held>,
+    /// Key combinations with an open multi-tap window
+    tap_sequences: HashMap<Vec<String>, TapSequence>,
+    /// Key sequence currently being entered
+    sequence: Option<SequenceProgress>,
+    next_id: u64,
+    /// Leave key names out of logs, set for each received key event
+    redact: bool,
+}
+
+impl Dispatcher {
+    pub fn new(layers: Layers, timing: Timing) -> Self {
+        Self {
+            layers,
+            layer_state: LayerState::new(),
+            timing,
+            held: HashMap::new(),
+            tap_sequences: HashMap::new(),
+            sequence: None,
+            next_id: 0,
+            redact: true,
+        }
+    }
+
+    pub fn layers(&self) -> &Layers {
+        &self.layers
+    }
+
+    pub fn layer_state(&self) -> &LayerState {
+        &self.layer_state
+    }
+
+    pub fn layer_state_mut(&mut self) -> &mut LayerState {
+        &mut self.layer_state
+    }
+
+    /// Whether key names of the current key event are left out of logs
+    pub fn redacts(&self) -> bool {
+        self.redact
+    }
+
+    pub fn set_redact(&mut self, redact: bool) {
+        self.redact = redact;
+    }
+
+    /// Steps of the key sequence entered so far, if one is in progress
+    pub fn sequence_progress(&self) -> Option<Vec<String>> {
+        self.sequence
+            .as_ref()
+            .map(|progress| progress.steps.iter().map(|s| s.join("+")).collect())
+    }
+
+    /// Binding of a pressed key combination in the active layer
+    pub fn binding(&self, keys: &[String]) -> Option<&Binding> {
+        let lookup_keys = self.layer_state.lookup_keys(keys);
+        self.layers.lookup(self.layer_state.active(), &lookup_keys)
+    }
+
+    /// Debounce window of a pressed key combination's binding in the active layer
+    pub fn debounce(&self, keys: &[String]) -> Duration {
+        self.binding(keys)
+            .and_then(|binding| binding.debounce)
+            .unwrap_or(self.timing.debounce)
+    }
+
+    /// Handle a newly pressed key combination
+    pub fn press(&mut self, keys: &[String]) -> Press {
+        self.layer_state.update_held(keys);
+        let mut effects = self.forget_released(keys);
+
+        let layer = self.layer_state.active().to_string();
+        let lookup_keys = self.layer_state.lookup_keys(keys);
+
+        // Key sequences take precedence over single key combinations
+        if let Some(sequence_effects) = self.advance_sequence(&layer, &lookup_keys) {
+            effects.extend(sequence_effects);
+            return Press {
+                effects,
+                matched: true,
+            };
+        }
+
+        let Some(binding) = self.layers.lookup(&layer, &lookup_keys).cloned() else {
+            debug!(
+                keys = %log_keys(&lookup_keys, self.redact),
+                layer = %layer,
+                "No command mapped for keys"
+            );
+            return Press {
+                effects,
+                matched: false,
+            };
+        };
+
+        if !binding.taps.is_empty() {
+            effects.extend(self.press_multi_tap(keys, binding));
+            return Press {
+                effects,
+                matched: true,
+            };
+        }
+
+        if let Some(action) = &binding.press {
+            effects.extend(self.perform(keys, &binding.id, action));
+        }
+
+        if binding.tap.is_some()
+            || binding.hold.is_some()
+            || binding.release.is_some()
+            || binding.repeat != Repeat::Ignore
+        {
+            let id = self.next_id();
+
+            if binding.hold.is_some() {
+                effects.push(Effect::Schedule {
+                    timer: Timer::Hold(id),
+                    after: binding.hold_after,
+                });
+            }
+            if let Repeat::IntervalMs(ms) = binding.repeat {
+                effects.push(Effect::Schedule {
+                    timer: Timer::Repeat(id),
+                    after: Duration::from_millis(ms),
+                });
+            }
+
+            self.held.insert(
+                keys.to_vec(),
+                Held {
+                    id,
+                    binding,
+                    hold_fired: false,
+                    tap_on_release: true,
+                },
+            );
+        }
+
+        Press {
+            effects,
+            matched: true,
+        }
+    }
+
+    /// Count a press of a multi-tap binding
+    ///
+    /// The single-tap `press` and `tap` actions are deferred until the tap
+    /// window closes without another press. Reaching the highest configured
+    /// tap count fires that action right away.
+    fn press_multi_tap(&mut self, keys: &[String], binding: Binding) -> Vec<Effect> {
+        let id = self.next_id();
+        let count = self.tap_sequences.get(keys).map_or(1, |seq| seq.count + 1);
+        let max_taps = binding.taps.keys().max().copied().unwrap_or(1);
+
+        if count >= max_taps {
+            self.tap_sequences.remove(keys);
+            debug!(binding = %binding.id, "Tapped {count} times");
+            return match binding.taps.get(&count) {
+                Some(action) => self
+                    .perform(keys, &binding.id, action)
+                    .into_iter()
+                    .collect(),
+                None => Vec::new(),
+            };
+        }
+
+        let mut effects = vec![Effect::Schedule {
+            timer: Timer::TapWindow(id),
+            after: self.timing.tap_window,
+        }];
+
+        // Only the first press of a sequence can become a hold or a single tap
+        if count == 1 && (binding.tap.is_some() || binding.hold.is_some()) {
+            if binding.hold.is_some() {
+                effects.push(Effect::Schedule {
+                    timer: Timer::Hold(id),
+                    after: binding.hold_after,
+                });
+            }
+
+            self.held.insert(
+                keys.to_vec(),
+                Held {
+                    id,
+                    binding: binding.clone(),
+                    hold_fired: false,
+                    tap_on_release: false,
+                },
+            );
+        }
+
+        self.tap_sequences
+            .insert(keys.to_vec(), TapSequence { id, count, binding });
+
+        effects
+    }
+
+    /// Handle an auto-repeat event of a held key
+    ///
+    /// Performs the press action of every held combination that includes the
+    /// key and repeats along with the keyboard.
+    pub fn repeat(&mut self, key: &str) -> Vec<Effect> {
+        let repeating: Vec<(Vec<String>, String, Action)> = self
+            .held
+            .iter()
+            .filter(|(held_keys, held)| {
+                held.binding.repeat == Repeat::Kernel && held_keys.iter().any(|k| k == key)
+            })
+            .filter_map(|(held_keys, held)| {
+                let action = held.binding.press.clone()?;
+                Some((held_keys.clone(), held.binding.id.clone(), action))
+            })
+            .collect();
+
+        repeating
+            .iter()
+            .filter_map(|(keys, binding, action)| self.perform(keys, binding, action))
+            .collect()
+    }
+
+    /// Handle the release of a key
+    ///
+    /// `keys` is the set of keys that was held right before the release and
+    /// `key` the released key. Older clients don't report the released key,
+    /// in which case only the combination matching `keys` exactly is released.
+    pub fn release(&mut self, keys: &[String], key: Option<&str>) -> Vec<Effect> {
+        self.layer_state.release(keys, key);
+        let mut effects = self.forget_released(keys);
+
+        let released: Vec<Vec<String>> = self
+            .held
+            .keys()
+            .filter(|held_keys| match key {
+                Some(key) => held_keys.iter().any(|k| k == key),
+                None => held_keys.as_slice() == keys,
+            })
+            .cloned()
+            .collect();
+
+        for held_keys in released {
+            let Some(held) = self.held.remove(&held_keys) else {
+                continue;
+            };
+
+            if let Some(action) = &held.binding.tap {
+                if held.tap_on_release && !held.hold_fired {
+                    effects.extend(self.perform(&held_keys, &held.binding.id, action));
+                }
+            }
+            if let Some(action) = &held.binding.release {
+                effects.extend(self.perform(&held_keys, &held.binding.id, action));
+            }
+        }
+
+        effects
+    }
+
+    /// Handle a timer returned in [`Effect::Schedule`] expiring
+    pub fn expire(&mut self, timer: Timer) -> Vec<Effect> {
+        match timer {
+            Timer::Hold(id) => {
+                let Some((keys, held)) = self.held.iter_mut().find(|(_, held)| held.id == id)
+                else {
+                    // Released before the threshold
+                    return Vec::new();
+                };
+
+                held.hold_fired = true;
+                let keys = keys.clone();
+                let binding = held.binding.id.clone();
+                let Some(action) = held.binding.hold.clone() else {
+                    return Vec::new();
+                };
+
+                debug!(binding = %binding, "Held for {:?}", held.binding.hold_after);
+
+                // A hold ends any multi-tap sequence it started
+                self.tap_sequences.remove(&keys);
+
+                self.perform(&keys, &binding, &action).into_iter().collect()
+            }
+            Timer::TapWindow(id) => {
+                let Some(keys) = self
+                    .tap_sequences
+                    .iter()
+                    .find(|(_, seq)| seq.id == id)
+                    .map(|(keys, _)| keys.clone())
+                else {
+                    // Tapped again, or ended by a hold
+                    return Vec::new();
+                };
+                let Some(seq) = self.tap_sequences.remove(&keys) else {
+                    return Vec::new();
+                };
+
+                debug!(binding = %seq.binding.id, "Tapped {} times", seq.count);
+
+                if seq.count == 1 {
+                    return self.single_tap(&keys, &seq.binding);
+                }
+                match seq.binding.taps.get(&seq.count) {
+                    Some(action) => self
+                        .perform(&keys, &seq.binding.id, action)
+                        .into_iter()
+                        .collect(),
+                    None => Vec::new(),
+                }
+            }
+            Timer::Repeat(id) => {
+                let Some((keys, held)) = self.held.iter().find(|(_, held)| held.id == id) else {
+                    // Released since the last repeat
+                    return Vec::new();
+                };
+                let (Some(action), Repeat::IntervalMs(ms)) =
+                    (held.binding.press.clone(), held.binding.repeat)
+                else {
+                    return Vec::new();
+                };
+                let keys = keys.clone();
+                let binding = held.binding.id.clone();
+
+                let mut effects: Vec<Effect> =
+                    self.perform(&keys, &binding, &action).into_iter().collect();
+                effects.push(Effect::Schedule {
+                    timer: Timer::Repeat(id),
+                    after: Duration::from_millis(ms),
+                });
+                effects
+            }
+            Timer::Sequence(id) => {
+                if let Some(progress) = self.sequence.take_if(|progress| progress.id == id) {
+                    debug!(
+                        "Key sequence timed out after {} steps",
+                        progress.steps.len()
+                    );
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    /// Match a pressed key combination against the key sequences
+    ///
+    /// Returns `None` when the keys are not part of any sequence, in which case
+    /// they are looked up as a regular key combination.
+    fn advance_sequence(&mut self, active_layer: &str, keys: &[String]) -> Option<Vec<Effect>> {
+        let (layer, mut steps) = match &self.sequence {
+            Some(progress) => (progress.layer.clone(), progress.steps.clone()),
+            None => (active_layer.to_string(), Vec::new()),
+        };
+        steps.push(keys.to_vec());
+
+        let mut candidates = 0;
+        let mut complete = None;
+        for seq in self.layers.sequences(&layer) {
+            if seq.steps == steps {
+                complete = Some((seq.id.clone(), seq.action.clone()));
+            } else if seq.steps.starts_with(&steps) {
+                candidates += 1;
+            }
+        }
+
+        if let Some((binding, action)) = complete {
+            self.sequence = None;
+            debug!(binding = %binding, "Key sequence complete");
+            return Some(self.perform(keys, &binding, &action).into_iter().collect());
+        }
+
+        if candidates == 0 {
+            let progress = self.sequence.take()?;
+
+            // Modifiers pressed on the way to a chord step keep the sequence going
+            let next = progress.steps.len();
+            let partial_chord = self.layers.sequences(&progress.layer).any(|seq| {
+                seq.steps.starts_with(&progress.steps)
+                    && seq.steps.get(next).is_some_and(|step| {
+                        step.len() > keys.len() && keys.iter().all(|k| step.contains(k))
+                    })
+            });
+            if partial_chord {
+                self.sequence = Some(progress);
+                return Some(Vec::new());
+            }
+
+            debug!(
+                keys = %log_keys(keys, self.redact),
+                "Key sequence broken after {} steps",
+                progress.steps.len()
+            );
+
+            // The breaking keys may start a new sequence
+            return self.advance_sequence(active_layer, keys);
+        }
+
+        let id = self.next_id();
+        debug!(
+            keys = %log_keys(keys, self.redact),
+            "Key sequence at step {} ({candidates} possible sequences)",
+            steps.len()
+        );
+        self.sequence = Some(SequenceProgress { id, layer, steps });
+
+        Some(vec![Effect::Schedule {
+            timer: Timer::Sequence(id),
+            after: self.timing.sequence_timeout,
+        }])
+    }
+
+    /// Perform the single-tap actions of a multi-tap binding once its window closed
+    fn single_tap(&mut self, keys: &[String], binding: &Binding) -> Vec<Effect> {
+        let mut effects = Vec::new();
+        if let Some(action) = &binding.press {
+            effects.extend(self.perform(keys, &binding.id, action));
+        }
+
+        if let Some(action) = &binding.tap {
+            match self.held.get_mut(keys) {
+                // Still held: the tap action fires on release unless it becomes a hold
+                Some(held) => held.tap_on_release = true,
+                None => effects.extend(self.perform(keys, &binding.id, action)),
+            }
+        }
+
+        effects
+    }
+
+    fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    /// Drop pending state of combinations that are no longer fully held
+    ///
+    /// Their release was missed, so only their release actions are performed.
+    fn forget_released(&mut self, keys: &[String]) -> Vec<Effect> {
+        let missed: Vec<Vec<String>> = self
+            .held
+            .keys()
+            .filter(|held_keys| !held_keys.iter().all(|k| keys.contains(k)))
+            .cloned()
+            .collect();
+
+        let mut effects = Vec::new();
+        for held_keys in missed {
+            let Some(held) = self.held.remove(&held_keys) else {
+                continue;
+            };
+            if let Some(action) = &held.binding.release {
+                effects.extend(self.perform(&held_keys, &held.binding.id, action));
+            }
+        }
+        effects
+    }
+
+    /// Perform an action of a binding triggered by a key combination
+    fn perform(&mut self, keys: &[String], binding: &str, action: &Action) -> Option<Effect> {
+        match action {
+            Action::Command(cmd) => {
+                self.layer_state.consume_one_shot();
+                Some(Effect::Run {
+                    cmd: cmd.clone(),
+                    keys: keys.to_vec(),
+                    binding: binding.to_string(),
+                })
+            }
+            Action::Layer(action) => {
+                self.layer_state.apply(keys, action);
+                info!(
+                    binding = %binding,
+                    layer = %self.layer_state.active(),
+                    "Applied {action}"
+                );
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::config::{BASE_LAYER, Config, parse_key_combination};
+
+    /// Dispatcher of the mappings of a config
+    fn dispatcher(config: &str) -> Dispatcher {
+        let config: Config = toml::from_str(&format!("user = \"test\"\n{config}")).unwrap();
+        Dispatcher::new(config.build_layers().unwrap(), config.timing())
+    }
+
+    fn keys(combination: &str) -> Vec<String> {
+        parse_key_combination(combination)
+    }
+
+    /// Commands run by effects, in order
+    fn commands(effects: &[Effect]) -> Vec<&str> {
+        effects
+            .iter()
+            .filter_map(|effect| match effect {
+                Effect::Run { cmd, .. } => Some(cmd.as_str()),
+                Effect::Schedule { .. } => None,
+            })
+            .collect()
+    }
+
+    /// Timers scheduled by effects, in order
+    fn timers(effects: &[Effect]) -> Vec<Timer> {
+        effects
+            .iter()
+            .filter_map(|effect| match effect {
+                Effect::Schedule { timer, .. } => Some(*timer),
+                Effect::Run { .. } => None,
+            })
+            .collect()
+    }
+
+    const TAP_HOLD: &str = r#"
+[[commands]]
+keys = "a"
+tap = { cmd = "tap" }
+hold = { cmd = "hold" }
+"#;
+
+    #[test]
+    fn release_before_the_hold_threshold_taps() {
+        let mut dispatcher = dispatcher(TAP_HOLD);
+
+        let press = dispatcher.press(&keys("a"));
+        let release = dispatcher.release(&keys("a"), Some("a"));
+        let expired = dispatcher.expire(timers(&press.effects)[0]);
+
+        assert!(press.matched);
+        assert!(commands(&press.effects).is_empty());
+        assert_eq!(commands(&release), vec!["tap"]);
+        assert!(expired.is_empty());
+    }
+
+    #[test]
+    fn hold_fires_at_the_threshold_and_skips_the_tap() {
+        let mut dispatcher = dispatcher(TAP_HOLD);
+
+        let press = dispatcher.press(&keys("a"));
+        let held = dispatcher.expire(timers(&press.effects)[0]);
+        let release = dispatcher.release(&keys("a"), Some("a"));
+
+        assert!(matches!(timers(&press.effects)[..], [Timer::Hold(_)]));
+        assert_eq!(commands(&held), vec!["hold"]);
+        assert!(release.is_empty());
+    }
+
+    const MULTI_TAP: &str = r#"
+[[commands]]
+keys = "a"
+cmd = "single"
+
+[[commands]]
+keys = "a"
+taps = 2
+cmd = "double"
+"#;
+
+    #[test]
+    fn single_tap_waits_for_the_tap_window_to_expire() {
+        let mut dispatcher = dispatcher(MULTI_TAP);
+
+        let press = dispatcher.press(&keys("a"));
+        let release = dispatcher.release(&keys("a"), Some("a"));
+        let expired = dispatcher.expire(timers(&press.effects)[0]);
+
+        assert!(commands(&press.effects).is_empty());
+        assert!(release.is_empty());
+        assert_eq!(commands(&expired), vec!["single"]);
+    }
+
+    #[test]
+    fn second_tap_within_the_window_fires_right_away() {
+        let mut dispatcher = dispatcher(MULTI_TAP);
+
+        let first = dispatcher.press(&keys("a"));
+        dispatcher.release(&keys("a"), Some("a"));
+        let second = dispatcher.press(&keys("a"));
+        let expired = dispatcher.expire(timers(&first.effects)[0]);
+
+        assert_eq!(commands(&second.effects), vec!["double"]);
+        assert!(expired.is_empty());
+    }
+
+    const SEQUENCE: &str = r#"
+[[commands]]
+sequence = ["f1", "a", "s"]
+cmd = "save"
+
+[[commands]]
+keys = "b"
+cmd = "b"
+"#;
+
+    #[test]
+    fn sequence_fires_after_its_last_step() {
+        let mut dispatcher = dispatcher(SEQUENCE);
+
+        let first = dispatcher.press(&keys("f1"));
+        dispatcher.press(&keys("a"));
+        let progress = dispatcher.sequence_progress();
+        let last = dispatcher.press(&keys("s"));
+
+        assert!(first.matched);
+        assert!(matches!(timers(&first.effects)[..], [Timer::Sequence(_)]));
+        assert_eq!(progress, Some(vec!["f1".to_string(), "a".to_string()]));
+        assert_eq!(commands(&last.effects), vec!["save"]);
+        assert_eq!(dispatcher.sequence_progress(), None);
+    }
+
+    #[test]
+    fn wrong_key_resets_the_sequence_and_is_handled_normally() {
+        let mut dispatcher = dispatcher(SEQUENCE);
+
+        dispatcher.press(&keys("f1"));
+        dispatcher.press(&keys("a"));
+        let wrong = dispatcher.press(&keys("b"));
+        let after = dispatcher.press(&keys("s"));
+
+        assert_eq!(commands(&wrong.effects), vec!["b"]);
+        assert_eq!(dispatcher.sequence_progress(), None);
+        assert!(!after.matched);
+        assert!(after.effects.is_empty());
+    }
+
+    #[test]
+    fn sequence_times_out_between_steps() {
+        let mut dispatcher = dispatcher(SEQUENCE);
+
+        let first = dispatcher.press(&keys("f1"));
+        dispatcher.expire(timers(&first.effects)[0]);
+        let after = dispatcher.press(&keys("a"));
+
+        assert_eq!(dispatcher.sequence_progress(), None);
+        assert!(!after.matched);
+    }
+
+    #[test]
+    fn release_action_pairs_with_the_press_action() {
+        let mut dispatcher = dispatcher(
+            r#"
+[[commands]]
+keys = "ctrl+m"
+on_press = { cmd = "unmute" }
+on_release = { cmd = "mute" }
+"#,
+        );
+
+        let press = dispatcher.press(&keys("ctrl+m"));
+        let other = dispatcher.release(&keys("ctrl+m"), Some("ctrl"));
+        let again = dispatcher.press(&keys("ctrl+m"));
+        let release = dispatcher.release(&keys("ctrl+m"), Some("m"));
+
+        assert_eq!(commands(&press.effects), vec!["unmute"]);
+        assert_eq!(commands(&other), vec!["mute"]);
+        assert_eq!(commands(&again.effects), vec!["unmute"]);
+        assert_eq!(commands(&release), vec!["mute"]);
+    }
+
+    #[test]
+    fn interval_repeat_fires_until_release() {
+        let mut dispatcher = dispatcher(
+            r#"
+[[commands]]
+keys = "up"
+cmd = "volume up"
+repeat = { interval_ms = 100 }
+"#,
+        );
+
+        let press = dispatcher.press(&keys("up"));
+        let repeated = dispatcher.expire(timers(&press.effects)[0]);
+        dispatcher.release(&keys("up"), Some("up"));
+        let released = dispatcher.expire(timers(&repeated)[0]);
+
+        assert_eq!(commands(&press.effects), vec!["volume up"]);
+        assert_eq!(commands(&repeated), vec!["volume up"]);
+        assert_eq!(timers(&repeated), timers(&press.effects));
+        assert!(released.is_empty());
+    }
+
+    #[test]
+    fn kernel_repeat_fires_only_for_repeating_bindings() {
+        let mut dispatcher = dispatcher(
+            r#"
+[[commands]]
+keys = "up"
+cmd = "volume up"
+repeat = "kernel"
+
+[[commands]]
+keys = "a"
+cmd = "a"
+"#,
+        );
+
+        dispatcher.press(&keys("up"));
+        let up = dispatcher.repeat("up");
+        dispatcher.release(&keys("up"), Some("up"));
+        dispatcher.press(&keys("a"));
+        let a = dispatcher.repeat("a");
+
+        assert_eq!(commands(&up), vec!["volume up"]);
+        assert!(a.is_empty());
+    }
+
+    const LAYERS: &str = r#"
+[[commands]]
+keys = "f1"
+layer = { momentary = "obs" }
+
+[[commands]]
+keys = "f2"
+layer = { one_shot = "obs" }
+
+[[commands]]
+keys = "a"
+cmd = "base a"
+
+[layers.obs]
+[[layers.obs.commands]]
+keys = "a"
+cmd = "obs a"
+"#;
+
+    #[test]
+    fn momentary_layer_ends_when_its_keys_are_released() {
+        let mut dispatcher = dispatcher(LAYERS);
+
+        dispatcher.press(&keys("f1"));
+        let layer = dispatcher.layer_state().active().to_string();
+        let held = dispatcher.press(&keys("f1+a"));
+        dispatcher.release(&keys("f1+a"), Some("a"));
+        dispatcher.release(&keys("f1"), Some("f1"));
+        let released = dispatcher.press(&keys("a"));
+
+        assert_eq!(layer, "obs");
+        assert_eq!(commands(&held.effects), vec!["obs a"]);
+        assert_eq!(dispatcher.layer_state().active(), BASE_LAYER);
+        assert_eq!(commands(&released.effects), vec!["base a"]);
+    }
+
+    #[test]
+    fn one_shot_layer_is_used_for_one_action() {
+        let mut dispatcher = dispatcher(LAYERS);
+
+        dispatcher.press(&keys("f2"));
+        dispatcher.release(&keys("f2"), Some("f2"));
+        let first = dispatcher.press(&keys("a"));
+        dispatcher.release(&keys("a"), Some("a"));
+        let second = dispatcher.press(&keys("a"));
+
+        assert_eq!(commands(&first.effects), vec!["obs a"]);
+        assert_eq!(commands(&second.effects), vec!["base a"]);
+    }
+}
@@ -0,0 +1,246 @@
+//! Running the commands of bindings.
+//!
+//! The service runs commands through an [`Executor`], so everything but the
+//! actual process spawning can be tested without root or a real user account.
+
+use super::units::{Transient, Units};
+use std::collections::{HashMap, HashSet};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, PoisonError};
+use tracing::info;
+
+/// A command to run on behalf of a binding
+pub struct Job<'a> {
+    /// ID of the binding that triggered the command
+    pub binding: &'a str,
+    /// User to run the command as
+    pub user: &'a str,
+    /// If it starts with '/', it's treated as an absolute path to a script,
+    /// optionally followed by arguments. Otherwise, it's run as a shell
+    /// command.
+    pub cmd: &'a str,
+    /// Variables to set for the command
+    pub env: &'a [(String, String)],
+}
+
+/// How far an [`Executor`] followed a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ran {
+    /// The command finished with an exit code, `None` if it was killed by a
+    /// signal
+    Exited(Option<i32>),
+    /// Another process started the command and takes care of it, so how it
+    /// exits isn't known
+    Launched,
+}
+
+/// Runs the commands of bindings
+pub trait Executor: Send + Sync {
+    /// Run a job's command and wait for it to finish, or for it to be
+    /// started if another process takes care of it
+    ///
+    /// # Errors
+    ///
+    /// Returns why the command couldn't be started.
+    fn execute(&self, job: &Job) -> Result<Ran, String>;
+}
+
+/// Whether running commands as `user` takes `runuser`
+///
+/// Commands run directly if `user` is the user this process runs as, e.g. in
+/// a per-user unit, and with `runuser` otherwise.
+///
+/// # Errors
+///
+/// `runuser` needs root, so without it commands can't run as another user.
+pub fn needs_runuser(user: &str) -> Result<bool, String> {
+    let euid = rustix::process::geteuid().as_raw();
+    if lookup_uid(user) == Some(euid) {
+        return Ok(false);
+    }
+    if euid != 0 {
+        return Err(format!(
+            "commands can't run as user '{user}' without root, and this runs as uid {euid}"
+        ));
+    }
+    Ok(true)
+}
+
+/// Pick how to run commands, with `runuser` if `runuser` is set and as
+/// units if they are configured
+pub fn for_user(runuser: bool, units: Option<Units>) -> Arc<dyn Executor> {
+    if !runuser {
+        info!(
+            "Running commands directly as uid {}",
+            rustix::process::geteuid().as_raw()
+        );
+    }
+
+    if let Some(units) = units {
+        if let Some(transient) = Transient::new(runuser, units) {
+            return Arc::new(transient);
+        }
+    }
+    if runuser {
+        Arc::new(Runuser)
+    } else {
+        Arc::new(Direct)
+    }
+}
+
+/// Look up the uid of a user by name
+pub(super) fn lookup_uid(user: &str) -> Option<u32> {
+    let output = Command::new("getent")
+        .args(["passwd", user])
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    // name:password:uid:gid:gecos:home:shell
+    String::from_utf8_lossy(&output.stdout)
+        .split(':')
+        .nth(2)?
+        .parse()
+        .ok()
+}
+
+/// Runs commands as the configured user with `runuser` and a login shell,
+/// which needs root
+pub struct Runuser;
+
+impl Executor for Runuser {
+    fn execute(&self, job: &Job) -> Result<Ran, String> {
+        run(Some(job.user), login_shell(job.cmd)?, job.env).map(Ran::Exited)
+    }
+}
+
+/// Runs commands with a login shell as the user the service runs as, for
+/// when that's already the configured user
+pub struct Direct;
+
+impl Executor for Direct {
+    fn execute(&self, job: &Job) -> Result<Ran, String> {
+        run(None, login_shell(job.cmd)?, job.env).map(Ran::Exited)
+    }
+}
+
+/// Run `args` with the variables of `env` set, as `user` with `runuser` if
+/// it's given, and wait for it to finish
+pub(super) fn run(
+    user: Option<&str>,
+    args: Vec<String>,
+    env: &[(String, String)],
+) -> Result<Option<i32>, String> {
+    let args = match user {
+        // Without --login, runuser keeps the environment it is given
+        Some(user) => ["runuser", "-u", user, "--"]
+            .into_iter()
+            .map(ToString::to_string)
+            .chain(args)
+            .collect(),
+        None => args,
+    };
+    let status = Command::new(&args[0])
+        .args(&args[1..])
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .status()
+        .map_err(|e| format!("Failed to execute {}: {e}", args[0]))?;
+    Ok(status.code())
+}
+
+/// `bash` running `cmd` in a login shell, which loads the user's profile
+pub(super) fn login_shell(cmd: &str) -> Result<Vec<String>, String> {
+    let mut args = vec!["/bin/bash".to_string(), "-l".to_string(), "-c".to_string()];
+    if cmd.starts_with('/') {
+        // Absolute path - split script path from arguments using shlex to respect quotes
+        let parts = shlex::split(cmd).ok_or("Failed to parse command: invalid quoting")?;
+        if parts.is_empty() {
+            return Err("Empty command".to_string());
+        }
+        // Use exec "$0" "$@" pattern to safely pass script path as $0
+        // and forward any additional arguments via $@
+        args.push("exec \"$0\" \"$@\"".to_string());
+        args.extend(parts);
+    } else {
+        // Shell command - run via bash -c
+        args.push(cmd.to_string());
+    }
+    Ok(args)
+}
+
+/// Records commands instead of running them, for tests
+///
+/// Clones share the recorded commands.
+#[derive(Clone, Default)]
+pub struct Recorder {
+    /// Commands in the order they were run
+    commands: Arc<Mutex<Vec<Run>>>,
+    /// Exit codes of commands that fail
+    failures: HashMap<String, i32>,
+    /// Commands that are only launched, like units
+    launched: HashSet<String>,
+}
+
+/// A command recorded by a [`Recorder`]
+struct Run {
+    user: String,
+    cmd: String,
+    env: Vec<(String, String)>,
+}
+
+impl Recorder {
+    /// Make `cmd` fail with `exit_code`
+    #[must_use]
+    pub fn fail(mut self, cmd: &str, exit_code: i32) -> Self {
+        self.failures.insert(cmd.to_string(), exit_code);
+        self
+    }
+
+    /// Make `cmd` only launched, the way units are
+    #[must_use]
+    pub fn launch(mut self, cmd: &str) -> Self {
+        self.launched.insert(cmd.to_string());
+        self
+    }
+
+    /// Users and commands run so far, oldest first
+    pub fn commands(&self) -> Vec<(String, String)> {
+        self.commands
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|run| (run.user.clone(), run.cmd.clone()))
+            .collect()
+    }
+
+    /// Environments of the commands run so far, oldest first
+    pub fn environments(&self) -> Vec<Vec<(String, String)>> {
+        self.commands
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|run| run.env.clone())
+            .collect()
+    }
+}
+
+impl Executor for Recorder {
+    fn execute(&self, job: &Job) -> Result<Ran, String> {
+        self.commands
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Run {
+                user: job.user.to_string(),
+                cmd: job.cmd.to_string(),
+                env: job.env.to_vec(),
+            });
+        if self.launched.contains(job.cmd) {
+            return Ok(Ran::Launched);
+        }
+        Ok(Ran::Exited(Some(
+            self.failures.get(job.cmd).copied().unwrap_or(0),
+        )))
+    }
+}
@@ -0,0 +1,105 @@
+//! Active layer tracking.
+//!
+//! The active layer is resolved in priority order: the most recently held
+//! momentary layer, then a pending one-shot layer, then the current layer
+//! selected by switch/toggle actions or `SetLayer`.
+
+use super::config::{BASE_LAYER, LayerAction};
+
+/// Runtime state of the layer stack
+#[derive(Debug)]
+pub struct LayerState {
+    /// Layer selected by switch/toggle actions or `SetLayer`
+    current: String,
+    /// Layer to return to when a toggled layer is toggled off
+    previous: String,
+    /// Momentary layers and the key combinations holding them, oldest first
+    momentary: Vec<(Vec<String>, String)>,
+    /// Layer active for the next action only
+    one_shot: Option<String>,
+}
+
+impl LayerState {
+    pub fn new() -> Self {
+        Self {
+            current: BASE_LAYER.to_string(),
+            previous: BASE_LAYER.to_string(),
+            momentary: Vec::new(),
+            one_shot: None,
+        }
+    }
+
+    /// Name of the layer used for lookups
+    pub fn active(&self) -> &str {
+        if let Some((_, layer)) = self.momentary.last() {
+            return layer;
+        }
+        self.one_shot.as_deref().unwrap_or(&self.current)
+    }
+
+    /// Keys to look up in the active layer
+    ///
+    /// While a momentary layer is held its keys are part of every combination
+    /// the capture daemon reports, so they are stripped before the lookup.
+    pub fn lookup_keys(&self, keys: &[String]) -> Vec<String> {
+        match self.momentary.last() {
+            Some((held, _)) => keys.iter().filter(|k| !held.contains(k)).cloned().collect(),
+            None => keys.to_vec(),
+        }
+    }
+
+    /// Drop momentary layers whose keys are no longer all held
+    ///
+    /// Called with the full set of held keys on every event.
+    pub fn update_held(&mut self, keys: &[String]) {
+        self.momentary
+            .retain(|(held, _)| held.iter().all(|k| keys.contains(k)));
+    }
+
+    /// Drop momentary layers ended by a key release
+    ///
+    /// `keys` is the set of keys held right before the release and `key` the
+    /// released key, if the client reported it. Without it, only a release
+    /// that reports exactly the momentary keys ends the layer.
+    pub fn release(&mut self, keys: &[String], key: Option<&str>) {
+        self.update_held(keys);
+        self.momentary.retain(|(held, _)| match key {
+            Some(key) => !held.iter().any(|k| k == key),
+            None => held.as_slice() != keys,
+        });
+    }
+
+    /// Apply a layer action triggered by a key combination
+    pub fn apply(&mut self, keys: &[String], action: &LayerAction) {
+        match action {
+            LayerAction::Momentary(layer) => {
+                self.momentary.push((keys.to_vec(), layer.clone()));
+            }
+            LayerAction::Toggle(layer) => {
+                if self.current == *layer {
+                    self.current = std::mem::replace(&mut self.previous, BASE_LAYER.to_string());
+                } else {
+                    self.previous = std::mem::replace(&mut self.current, layer.clone());
+                }
+                self.one_shot = None;
+            }
+            LayerAction::OneShot(layer) => {
+                self.one_shot = Some(layer.clone());
+            }
+            LayerAction::Switch(layer) => self.switch(layer),
+        }
+    }
+
+    /// Make a layer the current layer, clearing any pending one-shot layer
+    pub fn switch(&mut self, layer: &str) {
+        if self.current != layer {
+            self.previous = std::mem::replace(&mut self.current, layer.to_string());
+        }
+        self.one_shot = None;
+    }
+
+    /// Clear the one-shot layer after it has been used for an action
+    pub fn consume_one_shot(&mut self) {
+        self.one_shot = None;
+    }
+}
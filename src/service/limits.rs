@@ -0,0 +1,158 @@
+//! Limits on starting commands.
+//!
+//! Token buckets cap how fast commands start, across all bindings and for
+//! each binding, and a cap on running commands bounds how many child
+//! processes exist at a time. Together they give the service a ceiling even
+//! when a client floods it with key events.
+
+use super::config::LimitsConfig;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Instant;
+
+/// A limit that kept a command from starting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// Rate of commands across all bindings
+    Global,
+    /// Rate of commands of a single binding
+    Binding,
+    /// Number of running commands
+    Running,
+}
+
+impl Limit {
+    /// Label of the limit in metrics and logs
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Global => "global",
+            Self::Binding => "binding",
+            Self::Running => "running",
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Global => write!(f, "the global command rate limit"),
+            Self::Binding => write!(f, "the command rate limit of its binding"),
+            Self::Running => write!(f, "the limit on running commands"),
+        }
+    }
+}
+
+/// Commands allowed per minute, with bursts of up to `burst` commands
+#[derive(Debug, Clone, Copy)]
+struct Rate {
+    per_minute: u32,
+    burst: u32,
+}
+
+impl Rate {
+    /// Parse a rate limit setting, `None` if it's disabled
+    fn new(per_minute: u32, burst: u32, name: &str) -> Result<Option<Self>, String> {
+        if per_minute == 0 {
+            return Ok(None);
+        }
+        if burst == 0 {
+            return Err(format!(
+                "limits '{name}' must be greater than 0 when its rate is enabled"
+            ));
+        }
+        Ok(Some(Self { per_minute, burst }))
+    }
+}
+
+struct TokenBucket {
+    rate: Rate,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: f64::from(rate.burst),
+            updated: now,
+        }
+    }
+
+    /// Add the tokens earned since the last refill, then check for one
+    fn refill(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(self.rate.per_minute) / 60.0)
+            .min(f64::from(self.rate.burst));
+        self.updated = now;
+        self.tokens >= 1.0
+    }
+}
+
+/// Decides whether commands may start
+pub struct Limiter {
+    global: Option<TokenBucket>,
+    binding_rate: Option<Rate>,
+    /// Buckets of the bindings that started commands
+    bindings: HashMap<String, TokenBucket>,
+    max_running: Option<usize>,
+}
+
+impl Limiter {
+    pub fn from_config(config: &LimitsConfig) -> Result<Self, String> {
+        let now = Instant::now();
+        let global = Rate::new(config.commands_per_minute, config.burst, "burst")?
+            .map(|rate| TokenBucket::new(rate, now));
+        let binding_rate = Rate::new(
+            config.binding_commands_per_minute,
+            config.binding_burst,
+            "binding_burst",
+        )?;
+        let max_running = (config.max_running > 0).then_some(config.max_running);
+
+        Ok(Self {
+            global,
+            binding_rate,
+            bindings: HashMap::new(),
+            max_running,
+        })
+    }
+
+    /// Take what starting a command of `binding` needs, with `running`
+    /// commands already running
+    ///
+    /// Nothing is taken if a limit is hit.
+    pub fn admit(&mut self, binding: &str, running: usize) -> Result<(), Limit> {
+        if self.max_running.is_some_and(|max| running >= max) {
+            return Err(Limit::Running);
+        }
+
+        let now = Instant::now();
+        if let Some(global) = &mut self.global {
+            if !global.refill(now) {
+                return Err(Limit::Global);
+            }
+        }
+        let bucket = match self.binding_rate {
+            Some(rate) => {
+                let bucket = self
+                    .bindings
+                    .entry(binding.to_string())
+                    .or_insert_with(|| TokenBucket::new(rate, now));
+                if !bucket.refill(now) {
+                    return Err(Limit::Binding);
+                }
+                Some(bucket)
+            }
+            None => None,
+        };
+
+        if let Some(bucket) = bucket {
+            bucket.tokens -= 1.0;
+        }
+        if let Some(global) = &mut self.global {
+            global.tokens -= 1.0;
+        }
+        Ok(())
+    }
+}
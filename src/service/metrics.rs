@@ -0,0 +1,458 @@
+//! Relay statistics in the Prometheus text format.
+//!
+//! The service updates the counters as key events arrive and commands
+//! finish. They are exported over HTTP on a Unix socket or a loopback TCP
+//! port, or written to a file for `node_exporter`'s textfile collector.
+
+use super::config::{MetricsConfig, MetricsListen};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::Permissions;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tracing::{debug, error, info, warn};
+
+/// Upper bounds of the command duration histogram buckets in seconds
+const DURATION_BUCKETS: [f64; 8] = [0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 60.0];
+
+/// Largest HTTP request head accepted from a scraper
+const MAX_REQUEST_SIZE: usize = 8192;
+
+/// Time a scraper gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Content type of the Prometheus text format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Devices counted with their own label value, as clients choose device IDs
+const MAX_DEVICE_LABELS: usize = 32;
+
+/// Label value of the devices beyond `MAX_DEVICE_LABELS`
+const OTHER_DEVICES: &str = "other";
+
+/// Mode of the Unix socket of the metrics endpoint
+const SOCKET_MODE: u32 = 0o660;
+
+// ============================================================================
+// Counters
+// ============================================================================
+
+/// Statistics of the running service
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    /// Key presses by the ID of the binding they matched
+    key_presses: BTreeMap<String, u64>,
+    /// Key presses ignored within the debounce window
+    debounce_drops: u64,
+    /// Finished commands by binding ID and whether they succeeded
+    commands: BTreeMap<(String, bool), u64>,
+    /// Command run times by binding ID
+    command_durations: BTreeMap<String, Histogram>,
+    /// Events a device's capture daemon failed to send, by device
+    missed_events: BTreeMap<String, u64>,
+    /// Restarts of a device's event sequence, by device
+    device_reconnects: BTreeMap<String, u64>,
+    /// Commands that weren't started, by the limit they hit
+    rate_limited: BTreeMap<String, u64>,
+}
+
+/// Cumulative histogram over `DURATION_BUCKETS`
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+impl Metrics {
+    fn counters(&self) -> MutexGuard<'_, Counters> {
+        self.counters
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Count a key press that matched a binding
+    pub fn key_press(&self, binding: &str) {
+        *self
+            .counters()
+            .key_presses
+            .entry(binding.to_string())
+            .or_default() += 1;
+    }
+
+    /// Count a key press ignored within the debounce window
+    pub fn debounce_drop(&self) {
+        self.counters().debounce_drops += 1;
+    }
+
+    /// Record a finished command of a binding
+    pub fn command_finished(&self, binding: &str, success: bool, duration: Duration) {
+        let mut counters = self.counters();
+        *counters
+            .commands
+            .entry((binding.to_string(), success))
+            .or_default() += 1;
+        counters
+            .command_durations
+            .entry(binding.to_string())
+            .or_default()
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Count events a device's capture daemon failed to send
+    pub fn missed_events(&self, device: &str, count: u64) {
+        *device_entry(&mut self.counters().missed_events, device) += count;
+    }
+
+    /// Count a device whose capture daemon started a new event sequence
+    pub fn device_reconnect(&self, device: &str) {
+        *device_entry(&mut self.counters().device_reconnects, device) += 1;
+    }
+
+    /// Count a command that wasn't started because it hit a limit
+    pub fn rate_limited(&self, limit: &str) {
+        *self
+            .counters()
+            .rate_limited
+            .entry(limit.to_string())
+            .or_default() += 1;
+    }
+
+    /// Render all metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let counters = self.counters();
+        let mut out = String::new();
+
+        labeled_counter(
+            &mut out,
+            "ducky_key_presses_total",
+            "Key presses that matched a binding.",
+            "binding",
+            &counters.key_presses,
+        );
+
+        header(
+            &mut out,
+            "ducky_debounce_drops_total",
+            "counter",
+            "Key presses ignored within the debounce window.",
+        );
+        sample(
+            &mut out,
+            "ducky_debounce_drops_total",
+            &[],
+            counters.debounce_drops,
+        );
+
+        header(
+            &mut out,
+            "ducky_commands_total",
+            "counter",
+            "Finished commands by result.",
+        );
+        for ((binding, success), count) in &counters.commands {
+            let result = if *success { "success" } else { "failure" };
+            sample(
+                &mut out,
+                "ducky_commands_total",
+                &[("binding", binding), ("result", result)],
+                count,
+            );
+        }
+
+        header(
+            &mut out,
+            "ducky_command_duration_seconds",
+            "histogram",
+            "Time commands took to finish.",
+        );
+        for (binding, histogram) in &counters.command_durations {
+            for (bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets) {
+                sample(
+                    &mut out,
+                    "ducky_command_duration_seconds_bucket",
+                    &[("binding", binding), ("le", &bound.to_string())],
+                    count,
+                );
+            }
+            sample(
+                &mut out,
+                "ducky_command_duration_seconds_bucket",
+                &[("binding", binding), ("le", "+Inf")],
+                histogram.count,
+            );
+            sample(
+                &mut out,
+                "ducky_command_duration_seconds_sum",
+                &[("binding", binding)],
+                histogram.sum,
+            );
+            sample(
+                &mut out,
+                "ducky_command_duration_seconds_count",
+                &[("binding", binding)],
+                histogram.count,
+            );
+        }
+
+        labeled_counter(
+            &mut out,
+            "ducky_missed_events_total",
+            "Key events the capture daemon failed to send, e.g. because it couldn't connect.",
+            "device",
+            &counters.missed_events,
+        );
+        labeled_counter(
+            &mut out,
+            "ducky_device_reconnects_total",
+            "Times a device's capture daemon started over after a reconnect or restart.",
+            "device",
+            &counters.device_reconnects,
+        );
+        labeled_counter(
+            &mut out,
+            "ducky_rate_limited_total",
+            "Commands that weren't started because they hit a limit.",
+            "limit",
+            &counters.rate_limited,
+        );
+
+        out
+    }
+}
+
+/// Counter of a device, shared by all devices beyond `MAX_DEVICE_LABELS`
+fn device_entry<'a>(counts: &'a mut BTreeMap<String, u64>, device: &str) -> &'a mut u64 {
+    let device = if counts.contains_key(device) || counts.len() < MAX_DEVICE_LABELS {
+        device
+    } else {
+        OTHER_DEVICES
+    };
+    counts.entry(device.to_string()).or_default()
+}
+
+/// Render a counter with one sample per label value
+fn labeled_counter(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    label: &str,
+    counts: &BTreeMap<String, u64>,
+) {
+    header(out, name, "counter", help);
+    for (value, count) in counts {
+        sample(out, name, &[(label, value)], count);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn sample(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+    out.push_str(name);
+    if !labels.is_empty() {
+        out.push('{');
+        for (i, (label, value)) in labels.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+            let _ = write!(out, "{label}=\"{}\"", escape(value));
+        }
+        out.push('}');
+    }
+    let _ = writeln!(out, " {value}");
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// ============================================================================
+// Exporters
+// ============================================================================
+
+/// Where the service exports its metrics
+pub struct Exporter {
+    listen: Option<MetricsListen>,
+    textfile: Option<(PathBuf, Duration)>,
+}
+
+impl Exporter {
+    pub fn from_config(config: &MetricsConfig) -> Result<Self, String> {
+        let listen = config.listen()?;
+        let textfile = config
+            .textfile_interval()?
+            .and_then(|interval| Some((config.textfile.clone()?, interval)));
+        Ok(Self { listen, textfile })
+    }
+
+    /// Start exporting in the background, returning the Unix socket bound
+    /// for it, which the service removes when it exits
+    pub async fn start(self, metrics: &Arc<Metrics>) -> Option<PathBuf> {
+        let mut socket = None;
+        if let Some(listen) = self.listen {
+            match Endpoint::bind(&listen).await {
+                Ok(endpoint) => {
+                    info!("Serving metrics on {listen}");
+                    if let MetricsListen::Unix(path) = &listen {
+                        socket = Some(path.clone());
+                    }
+                    tokio::spawn(endpoint.serve(Arc::clone(metrics)));
+                }
+                Err(e) => error!("Failed to bind metrics endpoint {listen}: {e}"),
+            }
+        }
+
+        if let Some((path, interval)) = self.textfile {
+            info!("Writing metrics to '{}' every {interval:?}", path.display());
+            tokio::spawn(write_textfile(Arc::clone(metrics), path, interval));
+        }
+
+        socket
+    }
+}
+
+/// Listener of the HTTP metrics endpoint
+enum Endpoint {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+impl Endpoint {
+    /// Bind the endpoint, replacing a stale Unix socket
+    ///
+    /// A Unix socket gets `SOCKET_MODE` rather than one depending on the umask.
+    async fn bind(listen: &MetricsListen) -> io::Result<Self> {
+        match listen {
+            MetricsListen::Unix(path) => {
+                let _ = tokio::fs::remove_file(path).await;
+                let listener = UnixListener::bind(path)?;
+                tokio::fs::set_permissions(path, Permissions::from_mode(SOCKET_MODE)).await?;
+                Ok(Self::Unix(listener))
+            }
+            MetricsListen::Tcp(addr) => Ok(Self::Tcp(TcpListener::bind(addr).await?)),
+        }
+    }
+
+    /// Answer scrapes until the service exits
+    async fn serve(self, metrics: Arc<Metrics>) {
+        loop {
+            let accepted = match &self {
+                Self::Unix(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, _)| tokio::spawn(respond(stream, Arc::clone(&metrics)))),
+                Self::Tcp(listener) => listener
+                    .accept()
+                    .await
+                    .map(|(stream, _)| tokio::spawn(respond(stream, Arc::clone(&metrics)))),
+            };
+            if let Err(e) = accepted {
+                warn!("Failed to accept metrics connection: {e}");
+            }
+        }
+    }
+}
+
+/// Answer a single HTTP request with the metrics
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, metrics: Arc<Metrics>) {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(e)) => {
+            debug!("Failed to read metrics request: {e}");
+            return;
+        }
+        Err(_) => {
+            debug!("Metrics request timed out");
+            return;
+        }
+    };
+
+    let mut parts = request.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics" | "/")) => {
+            let body = metrics.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        debug!("Failed to send metrics: {e}");
+    }
+    let _ = stream.shutdown().await;
+}
+
+/// Read an HTTP request head, returning its request line
+async fn read_request<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<String> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too large",
+            ));
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    Ok(head.lines().next().unwrap_or_default().to_string())
+}
+
+/// Write the metrics to a textfile collector file every `interval`
+///
+/// The file is replaced atomically so `node_exporter` never reads a partial file.
+async fn write_textfile(metrics: Arc<Metrics>, path: PathBuf, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(e) = replace_file(&path, metrics.render()).await {
+            warn!("Failed to write metrics to '{}': {e}", path.display());
+        }
+    }
+}
+
+async fn replace_file(path: &Path, content: String) -> io::Result<()> {
+    // node_exporter only reads files ending in .prom
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, content).await?;
+    tokio::fs::rename(&tmp, path).await
+}
@@ -0,0 +1,1366 @@
+//! Keystroke varlink service.
+//!
+//! Listens for keystroke messages and executes configured commands as a
+//! specific user based on a TOML config file.
+//!
+//! # Service Behavior
+//!
+//! - Uses systemd's `Type=notify` for proper service readiness signaling
+//! - Self-terminates after a configurable period of inactivity (no keystroke
+//!   messages), once all spawned commands have finished
+//! - On SIGTERM or SIGINT, stops accepting connections and gives running
+//!   commands time to finish before exiting
+//! - Uses a monotonic clock to avoid issues with system time changes
+//! - Leaves key names out of logs unless the capture daemon marks the device
+//!   as a macro pad
+//! - Optionally exports relay statistics in the Prometheus text format
+//! - Checks every caller's peer credentials against the `[access]` allowlists
+//! - Rate limits commands and caps how many run at the same time
+//! - Keeps a history of triggered commands and how they finished, and streams
+//!   it to monitors; in dry-run mode commands are only logged and recorded
+//! - Reports how a started command is going by the ID key events return, so
+//!   callers can wait for it without blocking the service
+
+mod access;
+pub mod check;
+mod config;
+mod dispatch;
+mod executor;
+mod layers;
+mod limits;
+mod metrics;
+mod session;
+mod units;
+
+pub use executor::{Direct, Executor, Job, Ran, Recorder, Runuser};
+
+use crate::{
+    CaptureClient, CaptureDevice, CommandOutcome, CommandRecord, CommandStatus, ErrorReport,
+    HistoryResponse, KeyEvent, KeystrokeError, LayerResponse, MonitorEvent, Redact,
+    SendKeysResponse, ShutdownSignals, StatusResponse, default_socket, log_keys, timestamp_usec,
+};
+use access::{Peer, Permission};
+use config::{AccessConfig, Config, Layers, Timing};
+use dispatch::{Dispatcher, Effect};
+use futures_util::stream::{self, BoxStream, StreamExt};
+use limits::Limiter;
+use metrics::{Exporter, Metrics};
+use sd_notify::NotifyState;
+use session::Session;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tracing::{debug, error, field, info, warn};
+use units::Units;
+use zlink::connection::Socket;
+use zlink::connection::socket::UnixSocket;
+use zlink::{Connection, Reply, Server, service, unix};
+
+// ============================================================================
+// Constants
+// ============================================================================
+
+/// Interval for checking idle timeout
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Interval for checking whether running commands have finished
+const COMMAND_DRAIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Time running commands get to finish when the service is stopped
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Number of command records kept for `GetHistory`, and of started commands
+/// kept for `GetCommand`
+const HISTORY_SIZE: usize = 100;
+
+/// Number of commands queued for a `Monitor` caller before it misses some
+const MONITOR_QUEUE: usize = 64;
+
+/// Number of devices whose event sequences are tracked, as clients choose
+/// device IDs
+const MAX_DEVICES: usize = 32;
+
+/// Everything the server needs from a config file
+struct Loaded {
+    config: Config,
+    layers: Layers,
+    exporter: Exporter,
+    limiter: Limiter,
+    session: Session,
+    /// Units commands run as, if enabled
+    units: Option<Units>,
+}
+
+impl Loaded {
+    fn from_config(config: Config, path: &Path) -> Result<Self, String> {
+        let invalid = |e: String| format!("Invalid config file '{}': {e}", path.display());
+        let layers = config.build_layers().map_err(invalid)?;
+        let exporter = Exporter::from_config(&config.metrics).map_err(invalid)?;
+        let limiter = Limiter::from_config(&config.limits).map_err(invalid)?;
+        let session = Session::from_config(&config.session).map_err(invalid)?;
+        let units = Units::from_config(&config).map_err(invalid)?;
+        Ok(Self {
+            config,
+            layers,
+            exporter,
+            limiter,
+            session,
+            units,
+        })
+    }
+}
+
+// ============================================================================
+// Server
+// ============================================================================
+
+/// Check for systemd socket activation (`LISTEN_FDS` environment variable)
+/// Returns an `OwnedFd` if systemd passed us a socket
+fn get_systemd_socket() -> Option<OwnedFd> {
+    let listen_fds = std::env::var("LISTEN_FDS").ok()?;
+    let count: i32 = listen_fds.parse().ok()?;
+
+    if count >= 1 {
+        // SD_LISTEN_FDS_START is always 3 (first fd after stdin/stdout/stderr)
+        // SAFETY: systemd guarantees the fd is valid and is a Unix socket.
+        // We take ownership of the fd which will be closed when OwnedFd is dropped.
+        use std::os::unix::io::FromRawFd;
+        Some(unsafe { OwnedFd::from_raw_fd(3) })
+    } else {
+        None
+    }
+}
+
+/// Settings of the server from the command line and config file
+pub struct ServerConfig {
+    /// Path of the socket to bind without systemd socket activation
+    pub socket: PathBuf,
+    pub executor: Arc<dyn Executor>,
+    pub user: String,
+    pub access: AccessConfig,
+    pub layers: Layers,
+    pub timing: Timing,
+    pub idle_timeout: Option<Duration>,
+    pub redact: Redact,
+    /// Whether commands are recorded instead of executed at startup
+    pub dry_run: bool,
+    pub exporter: Exporter,
+    pub limiter: Limiter,
+    /// Environment of commands
+    pub session: Session,
+    /// Path of the loaded config file
+    pub path: PathBuf,
+    /// When the config file was loaded
+    pub loaded_at: SystemTime,
+    /// Why the config file couldn't be loaded, if it couldn't
+    pub config_error: Option<String>,
+}
+
+impl ServerConfig {
+    /// Load the config file at `path` and log what it configures
+    ///
+    /// A config file that can't be loaded leaves the service without
+    /// bindings, so clients learn why their keys do nothing instead of
+    /// failing to connect, and so does a user commands can't run as. Commands
+    /// are run as the configured user, directly if the service already runs as
+    /// that user, and the settings that don't come from the config file have
+    /// their defaults.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn load(path: PathBuf) -> Self {
+        let loaded_at = SystemTime::now();
+        let loaded = Config::load(&path)
+            .and_then(|c| Loaded::from_config(c, &path))
+            .and_then(|loaded| {
+                let runuser = executor::needs_runuser(&loaded.config.user)
+                    .map_err(|e| format!("Invalid config file '{}': {e}", path.display()))?;
+                Ok((loaded, runuser))
+            });
+        let ((loaded, runuser), config_error) = match loaded {
+            Ok(loaded) => (loaded, None),
+            Err(e) => {
+                error!("{e}");
+                warn!("Serving without bindings until the config file is fixed");
+                let loaded = Loaded::from_config(Config::unloaded(), &path)
+                    .expect("an empty config is valid");
+                ((loaded, false), Some(e))
+            }
+        };
+        let Loaded {
+            config,
+            layers,
+            exporter,
+            limiter,
+            session,
+            units,
+        } = loaded;
+
+        info!("Config file: {}", path.display());
+        if config_error.is_none() {
+            info!("Running commands as user: {}", config.user);
+        }
+        info!(
+            "Loaded {} command mappings in {} layers",
+            layers.binding_count(),
+            layers.names().len()
+        );
+
+        for (name, layer) in layers.iter() {
+            for (keys, binding) in &layer.bindings {
+                debug!(
+                    layer = %name,
+                    binding = %binding.id,
+                    keys = %keys.join("+"),
+                    "Binding {binding}"
+                );
+            }
+            for sequence in &layer.sequences {
+                let steps: Vec<String> = sequence.steps.iter().map(|s| s.join("+")).collect();
+                debug!(
+                    layer = %name,
+                    binding = %sequence.id,
+                    keys = %steps.join(" "),
+                    "Sequence {}",
+                    sequence.action
+                );
+            }
+        }
+
+        match config.idle_timeout() {
+            Some(timeout) => info!("Idle timeout: {timeout:?}"),
+            None => info!("Idle timeout disabled"),
+        }
+
+        Self {
+            socket: default_socket(),
+            executor: executor::for_user(runuser, units),
+            timing: config.timing(),
+            idle_timeout: config.idle_timeout(),
+            user: config.user,
+            access: config.access,
+            layers,
+            redact: Redact::Auto,
+            dry_run: false,
+            exporter,
+            limiter,
+            session,
+            path,
+            loaded_at,
+            config_error,
+        }
+    }
+}
+
+#[allow(clippy::missing_panics_doc)]
+pub async fn run_server(config: ServerConfig) {
+    let ServerConfig {
+        socket,
+        executor,
+        user,
+        access,
+        layers,
+        timing,
+        idle_timeout,
+        redact,
+        dry_run,
+        exporter,
+        limiter,
+        session,
+        path,
+        loaded_at,
+        config_error,
+    } = config;
+
+    // Only a socket bound by the service itself is removed on shutdown
+    let (listener, bound_socket) = match get_systemd_socket() {
+        Some(fd) => {
+            info!("Using socket from systemd (fd {})", fd.as_raw_fd());
+            let listener =
+                unix::Listener::try_from(fd).expect("Failed to convert systemd socket to listener");
+            (listener, None)
+        }
+        None => {
+            info!(
+                "No systemd socket, binding directly to: {}",
+                socket.display()
+            );
+            let _ = tokio::fs::remove_file(&socket).await;
+            let listener = unix::bind(&socket).expect("Failed to bind to socket");
+            (listener, Some(socket))
+        }
+    };
+
+    let mut signals = ShutdownSignals::new().expect("Failed to install signal handlers");
+
+    let start_time = Arc::new(Instant::now());
+    let last_activity = Arc::new(AtomicU64::new(0));
+    let metrics = Arc::new(Metrics::default());
+
+    let metrics_socket = exporter.start(&metrics).await;
+
+    let relay = Arc::new(Relay {
+        executor,
+        user,
+        dispatcher: Mutex::new(Dispatcher::new(layers, timing)),
+        running: AtomicUsize::new(0),
+        metrics: Arc::clone(&metrics),
+        last_error: Mutex::new(None),
+        limiter: Mutex::new(limiter),
+        session,
+        dry_run: AtomicBool::new(dry_run),
+        history: Mutex::new(VecDeque::with_capacity(HISTORY_SIZE)),
+        monitor: broadcast::channel(MONITOR_QUEUE).0,
+        commands: Mutex::new(BTreeMap::new()),
+        last_command: AtomicU64::new(0),
+    });
+    if dry_run {
+        info!("Dry run, commands are logged but not executed");
+    }
+    if let Some(e) = &config_error {
+        relay.record_error(e.clone());
+    }
+
+    let service = KeystrokeService {
+        relay: Arc::clone(&relay),
+        last_triggered: HashMap::new(),
+        start_time: Arc::clone(&start_time),
+        last_activity: Arc::clone(&last_activity),
+        last_sequence: HashMap::new(),
+        metrics,
+        redact,
+        config_path: path,
+        config_loaded: loaded_at,
+        idle_timeout,
+        clients: HashMap::new(),
+        access,
+        config_error,
+    };
+    let server = Server::new(listener, service);
+
+    let idle = async {
+        match idle_timeout {
+            Some(timeout) => wait_for_idle(&relay, &start_time, &last_activity, timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+
+    notify_systemd(NotifyState::Ready);
+
+    // Dropping the server future stops accepting connections
+    tokio::select! {
+        result = server.run() => match result {
+            Ok(()) => info!("Server done."),
+            Err(e) => error!("Server error: {e:?}"),
+        },
+        () = idle => info!("No activity for {} seconds, terminating.", idle_timeout.unwrap_or_default().as_secs()),
+        signal = signals.recv() => info!("Received {signal}, shutting down."),
+    }
+
+    let sockets: Vec<PathBuf> = bound_socket.into_iter().chain(metrics_socket).collect();
+    shutdown(&relay, &sockets).await;
+}
+
+fn notify_systemd(state: NotifyState<'_>) {
+    if std::env::var("NOTIFY_SOCKET").is_ok() {
+        if let Err(e) = sd_notify::notify(false, &[state]) {
+            warn!("Failed to notify systemd: {e}");
+        }
+    } else {
+        let _ = sd_notify::notify(false, &[state]);
+    }
+}
+
+/// Time since the last keystroke message
+fn idle_for(start_time: &Instant, last_activity: &AtomicU64) -> Duration {
+    let last_elapsed = last_activity.load(Ordering::Relaxed);
+    let current_elapsed = start_time.elapsed().as_secs();
+    Duration::from_secs(current_elapsed.saturating_sub(last_elapsed))
+}
+
+/// Wait until there was no activity for `idle_timeout` and no command is running
+async fn wait_for_idle(
+    relay: &Relay,
+    start_time: &Instant,
+    last_activity: &AtomicU64,
+    idle_timeout: Duration,
+) {
+    loop {
+        tokio::time::sleep(IDLE_CHECK_INTERVAL.min(idle_timeout)).await;
+
+        if idle_for(start_time, last_activity) < idle_timeout {
+            continue;
+        }
+
+        // Don't kill commands that are still running
+        let running = relay.running();
+        if running > 0 {
+            info!("Idle, waiting for {running} running command(s) to finish");
+            relay.wait_for_commands().await;
+
+            // A keystroke may have arrived in the meantime
+            if idle_for(start_time, last_activity) < idle_timeout {
+                continue;
+            }
+        }
+
+        return;
+    }
+}
+
+/// Tell systemd the service is stopping, give running commands up to
+/// `SHUTDOWN_TIMEOUT` to finish and remove the sockets the service bound
+async fn shutdown(relay: &Relay, bound_sockets: &[PathBuf]) -> ! {
+    notify_systemd(NotifyState::Stopping);
+
+    let running = relay.running();
+    if running > 0 {
+        info!("Waiting up to {SHUTDOWN_TIMEOUT:?} for {running} running command(s)");
+        if tokio::time::timeout(SHUTDOWN_TIMEOUT, relay.wait_for_commands())
+            .await
+            .is_err()
+        {
+            warn!(
+                "{} command(s) still running, exiting anyway",
+                relay.running()
+            );
+        }
+    }
+
+    for path in bound_sockets {
+        if let Err(e) = std::fs::remove_file(path) {
+            warn!("Failed to remove socket '{}': {e}", path.display());
+        }
+    }
+
+    // Threads of commands that are still running would keep the runtime alive
+    std::process::exit(0);
+}
+
+// ============================================================================
+// Service Implementation
+// ============================================================================
+
+/// State shared between the service and the timers it schedules
+struct Relay {
+    executor: Arc<dyn Executor>,
+    user: String,
+    dispatcher: Mutex<Dispatcher>,
+    /// Number of spawned commands that haven't finished yet
+    running: AtomicUsize,
+    /// Statistics, shared with the service
+    metrics: Arc<Metrics>,
+    /// Most recent error, reported by `GetStatus`
+    last_error: Mutex<Option<ErrorReport>>,
+    /// Rate limits and the cap on running commands
+    limiter: Mutex<Limiter>,
+    /// Environment of commands
+    session: Session,
+    /// Whether commands are logged and recorded instead of executed
+    dry_run: AtomicBool,
+    /// Most recently triggered commands, reported by `GetHistory`
+    history: Mutex<VecDeque<CommandRecord>>,
+    /// Sends triggered commands to `Monitor` callers
+    monitor: broadcast::Sender<CommandRecord>,
+    /// Most recently started commands by ID, reported by `GetCommand`
+    commands: Mutex<BTreeMap<u64, Tracked>>,
+    /// ID of the most recently started command
+    last_command: AtomicU64,
+}
+
+/// A started command, followed for `GetCommand`
+struct Tracked {
+    binding: String,
+    started: Instant,
+    /// How the command finished, or why it failed, `None` while it runs
+    finished: Option<Result<CommandOutcome, String>>,
+}
+
+impl Relay {
+    fn dispatcher(&self) -> MutexGuard<'_, Dispatcher> {
+        self.dispatcher
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn last_error(&self) -> MutexGuard<'_, Option<ErrorReport>> {
+        self.last_error
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn limiter(&self) -> MutexGuard<'_, Limiter> {
+        self.limiter
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn history(&self) -> MutexGuard<'_, VecDeque<CommandRecord>> {
+        self.history
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn commands(&self) -> MutexGuard<'_, BTreeMap<u64, Tracked>> {
+        self.commands
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    fn dry_run(&self) -> bool {
+        self.dry_run.load(Ordering::Relaxed)
+    }
+
+    /// Remember what happened to a triggered command for `GetHistory` and
+    /// send it to `Monitor` callers
+    fn record_command(
+        &self,
+        id: Option<u64>,
+        binding: &str,
+        cmd: &str,
+        keys: Option<Vec<String>>,
+        outcome: CommandOutcome,
+        message: Option<String>,
+    ) {
+        let record = CommandRecord {
+            id,
+            binding: binding.to_string(),
+            command: cmd.to_string(),
+            keys,
+            outcome,
+            message,
+            timestamp_usec: timestamp_usec(SystemTime::now()),
+        };
+
+        {
+            let mut history = self.history();
+            if history.len() == HISTORY_SIZE {
+                history.pop_front();
+            }
+            history.push_back(record.clone());
+        }
+        // Fails only if nobody is monitoring
+        let _ = self.monitor.send(record);
+    }
+
+    /// Remember an error for `GetStatus`
+    fn record_error(&self, message: String) {
+        *self.last_error() = Some(ErrorReport {
+            message,
+            timestamp_usec: timestamp_usec(SystemTime::now()),
+        });
+    }
+
+    fn running(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Follow a command that is about to start, returning its ID
+    fn track(&self, binding: &str) -> u64 {
+        let id = self.last_command.fetch_add(1, Ordering::Relaxed) + 1;
+        let mut commands = self.commands();
+        if commands.len() == HISTORY_SIZE {
+            commands.pop_first();
+        }
+        commands.insert(
+            id,
+            Tracked {
+                binding: binding.to_string(),
+                started: Instant::now(),
+                finished: None,
+            },
+        );
+        id
+    }
+
+    /// How a started command is going
+    ///
+    /// A command that failed is reported as `CommandFailed`, and one still
+    /// running `timeout` after it started as `CommandTimeout`.
+    fn command_status(
+        &self,
+        id: u64,
+        timeout: Option<Duration>,
+    ) -> Result<CommandStatus, KeystrokeError> {
+        let commands = self.commands();
+        let Some(command) = commands.get(&id) else {
+            return Err(KeystrokeError::UnknownCommand { id });
+        };
+        let binding = command.binding.clone();
+        let outcome = match &command.finished {
+            Some(Ok(outcome)) => *outcome,
+            Some(Err(message)) => {
+                return Err(KeystrokeError::CommandFailed {
+                    binding,
+                    message: message.clone(),
+                });
+            }
+            None if timeout.is_some_and(|timeout| command.started.elapsed() >= timeout) => {
+                return Err(KeystrokeError::CommandTimeout { binding });
+            }
+            None => CommandOutcome::Started,
+        };
+        Ok(CommandStatus {
+            id,
+            binding,
+            outcome,
+        })
+    }
+
+    /// Wait until all spawned commands have finished
+    async fn wait_for_commands(&self) {
+        while self.running() > 0 {
+            tokio::time::sleep(COMMAND_DRAIN_INTERVAL).await;
+        }
+    }
+
+    /// Carry out effects returned by the dispatcher, returning the IDs of
+    /// the commands that were started
+    ///
+    /// Commands that hit a limit aren't started, and the first limit hit is
+    /// returned once the other effects have been carried out. In dry-run
+    /// mode commands are only logged and recorded.
+    fn apply(self: &Arc<Self>, effects: Vec<Effect>) -> Result<Vec<u64>, KeystrokeError> {
+        let redact = self.dispatcher().redacts();
+        let mut started = Vec::new();
+        let mut refused = None;
+        for effect in effects {
+            match effect {
+                Effect::Run { cmd, keys, binding } => {
+                    let recorded = (!redact).then(|| keys.clone());
+                    let keys = log_keys(&keys, redact).to_string();
+
+                    if self.dry_run() {
+                        info!(
+                            binding = %binding,
+                            keys = %keys,
+                            "Dry run, not executing '{cmd}' as user '{}'",
+                            self.user
+                        );
+                        self.record_command(
+                            None,
+                            &binding,
+                            &cmd,
+                            recorded,
+                            CommandOutcome::DryRun,
+                            None,
+                        );
+                        continue;
+                    }
+
+                    // Count the command as running until it exits, while
+                    // holding the limiter so the cap can't be overshot
+                    {
+                        let mut limiter = self.limiter();
+                        if let Err(limit) = limiter.admit(&binding, self.running()) {
+                            warn!(
+                                binding = %binding,
+                                keys = %keys,
+                                limit = limit.label(),
+                                "Not executing '{cmd}', it hit {limit}"
+                            );
+                            self.metrics.rate_limited(limit.label());
+                            self.record_command(
+                                None,
+                                &binding,
+                                &cmd,
+                                recorded,
+                                CommandOutcome::RateLimited,
+                                None,
+                            );
+                            refused.get_or_insert(KeystrokeError::RateLimited {
+                                message: format!("Binding '{binding}' hit {limit}"),
+                            });
+                            continue;
+                        }
+                        self.running.fetch_add(1, Ordering::Relaxed);
+                    }
+
+                    let id = self.track(&binding);
+                    self.record_command(
+                        Some(id),
+                        &binding,
+                        &cmd,
+                        recorded.clone(),
+                        CommandOutcome::Started,
+                        None,
+                    );
+
+                    self.spawn(id, cmd, keys, recorded, binding);
+                    started.push(id);
+                }
+                Effect::Schedule { timer, after } => {
+                    let relay = Arc::clone(self);
+                    tokio::spawn(async move {
+                        tokio::time::sleep(after).await;
+                        let effects = relay.dispatcher().expire(timer);
+                        // There's no caller to tell about limits, they are logged
+                        let _ = relay.apply(effects);
+                    });
+                }
+            }
+        }
+
+        refused.map_or(Ok(started), Err)
+    }
+
+    /// Run a command in the background, already counted as running, and
+    /// record how it finished
+    ///
+    /// `keys` are the keys to log, `recorded` the ones to record.
+    fn spawn(
+        self: &Arc<Self>,
+        id: u64,
+        cmd: String,
+        keys: String,
+        recorded: Option<Vec<String>>,
+        binding: String,
+    ) {
+        info!(
+            binding = %binding,
+            keys = %keys,
+            "Executing '{cmd}' as user '{}'",
+            self.user
+        );
+
+        let relay = Arc::clone(self);
+        tokio::task::spawn_blocking(move || {
+            let started = Instant::now();
+            let env = relay.session.environment(&relay.user);
+            let result = relay.executor.execute(&Job {
+                binding: &binding,
+                user: &relay.user,
+                cmd: &cmd,
+                env: &env,
+            });
+            // How long a launched command runs and how it exits isn't known
+            if result != Ok(Ran::Launched) {
+                relay.metrics.command_finished(
+                    &binding,
+                    result == Ok(Ran::Exited(Some(0))),
+                    started.elapsed(),
+                );
+            }
+            let outcome = match result {
+                Ok(Ran::Launched) => {
+                    info!(
+                        binding = %binding,
+                        keys = %keys,
+                        "Command '{cmd}' was launched"
+                    );
+                    Ok(CommandOutcome::Launched)
+                }
+                Ok(Ran::Exited(Some(0))) => {
+                    info!(
+                        binding = %binding,
+                        keys = %keys,
+                        exit_code = 0,
+                        "Command '{cmd}' completed successfully"
+                    );
+                    Ok(CommandOutcome::Succeeded)
+                }
+                Ok(Ran::Exited(Some(code))) => {
+                    warn!(
+                        binding = %binding,
+                        keys = %keys,
+                        exit_code = code,
+                        "Command '{cmd}' failed with exit code {code}"
+                    );
+                    Err(format!("failed with exit code {code}"))
+                }
+                Ok(Ran::Exited(None)) => {
+                    warn!(
+                        binding = %binding,
+                        keys = %keys,
+                        "Command '{cmd}' was killed by a signal"
+                    );
+                    Err("was killed by a signal".to_string())
+                }
+                Err(e) => {
+                    error!(
+                        binding = %binding,
+                        keys = %keys,
+                        "Command '{cmd}' failed: {e}"
+                    );
+                    Err(format!("failed: {e}"))
+                }
+            };
+            let (outcome, message) = match outcome {
+                Ok(outcome) => (outcome, None),
+                Err(reason) => {
+                    relay.record_error(format!("Command '{cmd}' of binding '{binding}' {reason}"));
+                    (
+                        CommandOutcome::Failed,
+                        Some(format!("Command '{cmd}' {reason}")),
+                    )
+                }
+            };
+            if let Some(command) = relay.commands().get_mut(&id) {
+                command.finished = Some(message.clone().map_or(Ok(outcome), Err));
+            }
+            relay.record_command(Some(id), &binding, &cmd, recorded, outcome, message);
+            // Only once it's recorded, so whoever sees no commands running
+            // also sees how they went
+            relay.running.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
+
+struct KeystrokeService {
+    relay: Arc<Relay>,
+    /// Track last trigger time and debounce window for each key combination
+    last_triggered: HashMap<Vec<String>, (Instant, Duration)>,
+    /// Reference start time for monotonic clock (for idle timeout)
+    start_time: Arc<Instant>,
+    /// Shared elapsed seconds since `start_time` at last activity (for idle timeout)
+    last_activity: Arc<AtomicU64>,
+    /// Last `SendKeysV2` sequence number seen for each device
+    last_sequence: HashMap<String, u64>,
+    /// Statistics exported as metrics
+    metrics: Arc<Metrics>,
+    /// Whether key names are left out of logs
+    redact: Redact,
+    /// Path of the loaded config file
+    config_path: PathBuf,
+    /// When the config file was loaded
+    config_loaded: SystemTime,
+    idle_timeout: Option<Duration>,
+    /// Capture daemons by name, with their devices and when they were last seen
+    clients: HashMap<String, (Vec<CaptureDevice>, Instant)>,
+    /// Who may call which methods
+    access: AccessConfig,
+    /// Why the config file couldn't be loaded, if it couldn't
+    config_error: Option<String>,
+}
+
+impl KeystrokeService {
+    /// Fail key events while the service has no config
+    fn require_config(&self) -> Result<(), KeystrokeError> {
+        match &self.config_error {
+            Some(message) => Err(KeystrokeError::ConfigNotLoaded {
+                message: message.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Check that the caller on a connection may use a method
+    fn authorize<Sock: Socket>(
+        &self,
+        conn: &Connection<Sock>,
+        method: &str,
+        permission: Permission,
+    ) -> Result<(), KeystrokeError>
+    where
+        Sock::ReadHalf: UnixSocket,
+    {
+        let denied = || KeystrokeError::PermissionDenied {
+            method: method.to_string(),
+        };
+
+        let peer = Peer::of(conn.read().read_half()).map_err(|e| {
+            warn!("Failed to get the credentials of a {method} caller: {e}");
+            denied()
+        })?;
+        if !self.access.allows(&peer, permission) {
+            warn!("Denied {method} to {peer}");
+            return Err(denied());
+        }
+        Ok(())
+    }
+
+    /// Check and update the debounce timer for a pressed key combination
+    fn debounce(&mut self, normalized: &[String], redact: bool) -> bool {
+        let now = Instant::now();
+        let window = self.relay.dispatcher().debounce(normalized);
+
+        // Clean up stale debounce entries (older than their debounce window)
+        self.last_triggered
+            .retain(|_, (last_time, window)| now.duration_since(*last_time) < *window);
+
+        let should_trigger = match self.last_triggered.get(normalized) {
+            Some((last_time, _)) => {
+                let elapsed = now.duration_since(*last_time);
+                if elapsed >= window {
+                    debug!("Debounce window passed ({elapsed:?} >= {window:?}), allowing trigger");
+                    true
+                } else {
+                    debug!(
+                        keys = %log_keys(normalized, redact),
+                        "Ignoring key press within debounce window ({elapsed:?} < {window:?})"
+                    );
+                    false
+                }
+            }
+            None => {
+                debug!(
+                    keys = %log_keys(normalized, redact),
+                    "First press for this key combination"
+                );
+                true
+            }
+        };
+
+        // Always update the timer on every press - this resets the debounce window
+        // so rapid repeated presses won't trigger again until the window has passed
+        self.last_triggered
+            .insert(normalized.to_vec(), (now, window));
+
+        should_trigger
+    }
+
+    /// Handle a key event reported by `SendKeys` or `SendKeysV2`
+    ///
+    /// Key names are left out of logs if `redact` is set. With `strict` set,
+    /// pressing keys without a binding is an error.
+    fn handle_keys(
+        &mut self,
+        keys: Vec<String>,
+        pressed: bool,
+        key: Option<String>,
+        redact: bool,
+        strict: bool,
+    ) -> Result<SendKeysResponse, KeystrokeError> {
+        self.last_activity
+            .store(self.start_time.elapsed().as_secs(), Ordering::Relaxed);
+        self.relay.dispatcher().set_redact(redact);
+
+        let keys: Vec<String> = keys.into_iter().filter(|k| !k.trim().is_empty()).collect();
+
+        if keys.is_empty() {
+            return Err(KeystrokeError::InvalidKey {
+                message: "Keys list cannot be empty".to_string(),
+            });
+        }
+
+        // Normalize keys for lookup (same as parse_key_combination)
+        let mut normalized: Vec<String> = keys.iter().map(|k| k.to_lowercase()).collect();
+        normalized.sort();
+
+        // The changed key is part of the held set, even for a release
+        let key = key.map(|k| k.trim().to_lowercase());
+        if let Some(key) = &key {
+            if !normalized.contains(key) {
+                return Err(KeystrokeError::InvalidKey {
+                    message: format!("Changed key '{key}' is not in the keys list"),
+                });
+            }
+        }
+
+        debug!(
+            keys = %log_keys(&normalized, redact),
+            key = key
+                .as_ref()
+                .map(|k| field::display(log_keys(slice::from_ref(k), redact))),
+            pressed,
+            "Received key combination"
+        );
+
+        // Releases end momentary layers and resolve pending tap/hold/release actions
+        if !pressed {
+            let effects = self.relay.dispatcher().release(&normalized, key.as_deref());
+            let started = self.relay.apply(effects)?;
+
+            return Ok(self.send_keys_response(normalized, false, started));
+        }
+
+        // Key press event - check debounce
+        if !self.debounce(&normalized, redact) {
+            self.metrics.debounce_drop();
+            // pressed: false indicates no action taken due to debounce
+            return Ok(self.send_keys_response(normalized, false, Vec::new()));
+        }
+
+        let press = {
+            let mut dispatcher = self.relay.dispatcher();
+            if let Some(binding) = dispatcher.binding(&normalized) {
+                self.metrics.key_press(&binding.id);
+            }
+            dispatcher.press(&normalized)
+        };
+        let started = self.relay.apply(press.effects)?;
+
+        if strict && !press.matched {
+            return Err(KeystrokeError::UnknownBinding { keys: normalized });
+        }
+
+        Ok(self.send_keys_response(normalized, pressed, started))
+    }
+
+    /// Handle an auto-repeat event reported by `SendKeysV2`
+    ///
+    /// Repeats aren't new presses, so they bypass the debounce.
+    fn handle_repeat(
+        &mut self,
+        held: Vec<String>,
+        key: &str,
+        redact: bool,
+    ) -> Result<SendKeysResponse, KeystrokeError> {
+        self.last_activity
+            .store(self.start_time.elapsed().as_secs(), Ordering::Relaxed);
+        self.relay.dispatcher().set_redact(redact);
+
+        let key = key.trim().to_lowercase();
+        let mut normalized: Vec<String> = held.iter().map(|k| k.to_lowercase()).collect();
+        normalized.sort();
+
+        if !normalized.contains(&key) {
+            return Err(KeystrokeError::InvalidKey {
+                message: format!("Repeated key '{key}' is not in the keys list"),
+            });
+        }
+
+        let effects = self.relay.dispatcher().repeat(&key);
+        let started = self.relay.apply(effects)?;
+
+        Ok(self.send_keys_response(normalized, true, started))
+    }
+
+    /// Log events lost or reordered between the capture daemon and the service
+    fn track_sequence(&mut self, event: &KeyEvent) {
+        let latency = SystemTime::now()
+            .duration_since(UNIX_EPOCH + Duration::from_micros(event.timestamp_usec))
+            .unwrap_or_default();
+        debug!(
+            device = %event.device,
+            sequence = event.sequence,
+            "Event received {latency:?} after the kernel reported it"
+        );
+
+        if !self.last_sequence.contains_key(&event.device) && !self.make_room(&event.device) {
+            debug!(
+                device = %event.device,
+                "Not tracking the sequence of another device, {MAX_DEVICES} are already"
+            );
+            return;
+        }
+
+        let Some(last) = self
+            .last_sequence
+            .insert(event.device.clone(), event.sequence)
+        else {
+            return;
+        };
+
+        if event.sequence <= last {
+            self.metrics.device_reconnect(&event.device);
+            info!(
+                "Device '{}' restarted its sequence at #{} (last was #{last})",
+                event.device, event.sequence
+            );
+        } else if event.sequence > last + 1 {
+            let missed = event.sequence - last - 1;
+            self.metrics.missed_events(&event.device, missed);
+            warn!(
+                "Missed {missed} events from device '{}' (#{last} -> #{})",
+                event.device, event.sequence
+            );
+            self.relay.record_error(format!(
+                "Missed {missed} events from device '{}'",
+                event.device
+            ));
+        }
+    }
+
+    /// Whether the sequence of another device can be tracked
+    ///
+    /// Once `MAX_DEVICES` are tracked, a device announced with
+    /// `ReportDevices` takes the place of those that weren't.
+    fn make_room(&mut self, device: &str) -> bool {
+        if self.last_sequence.len() < MAX_DEVICES {
+            return true;
+        }
+        if !self.is_announced(device) {
+            return false;
+        }
+
+        let announced: Vec<String> = self
+            .last_sequence
+            .keys()
+            .filter(|tracked| self.is_announced(tracked))
+            .cloned()
+            .collect();
+        self.last_sequence
+            .retain(|tracked, _| announced.contains(tracked));
+        self.last_sequence.len() < MAX_DEVICES
+    }
+
+    /// Whether a capture daemon reported the device with `ReportDevices`
+    fn is_announced(&self, device: &str) -> bool {
+        self.clients
+            .values()
+            .any(|(devices, _)| devices.iter().any(|d| d.id == device))
+    }
+
+    fn send_keys_response(
+        &self,
+        keys: Vec<String>,
+        pressed: bool,
+        commands: Vec<u64>,
+    ) -> SendKeysResponse {
+        SendKeysResponse {
+            success: true,
+            keys,
+            pressed,
+            sequence: self.relay.dispatcher().sequence_progress(),
+            commands,
+        }
+    }
+
+    fn layer_response(&self) -> LayerResponse {
+        let dispatcher = self.relay.dispatcher();
+        LayerResponse {
+            layer: dispatcher.layer_state().active().to_string(),
+            layers: dispatcher.layers().names(),
+        }
+    }
+
+    /// Mark the capture daemon that reported a device as seen
+    fn device_seen(&mut self, device: &str) {
+        for (devices, last_seen) in self.clients.values_mut() {
+            if devices.iter().any(|d| d.id == device) {
+                *last_seen = Instant::now();
+            }
+        }
+    }
+
+    fn status_response(&self) -> StatusResponse {
+        let mut clients: Vec<CaptureClient> = self
+            .clients
+            .iter()
+            .map(|(client, (devices, last_seen))| CaptureClient {
+                client: client.clone(),
+                devices: devices.clone(),
+                last_seen_secs: last_seen.elapsed().as_secs(),
+            })
+            .collect();
+        clients.sort_by(|a, b| a.client.cmp(&b.client));
+
+        let idle = idle_for(&self.start_time, &self.last_activity);
+        let dispatcher = self.relay.dispatcher();
+        StatusResponse {
+            uptime_secs: self.start_time.elapsed().as_secs(),
+            config: self.config_path.display().to_string(),
+            config_loaded_usec: timestamp_usec(self.config_loaded),
+            bindings: dispatcher.layers().binding_count() as u64,
+            layer: dispatcher.layer_state().active().to_string(),
+            clients,
+            running_commands: self.relay.running() as u64,
+            idle_exit_secs: self
+                .idle_timeout
+                .map(|timeout| timeout.saturating_sub(idle).as_secs()),
+            last_error: self.relay.last_error().clone(),
+            dry_run: self.relay.dry_run(),
+        }
+    }
+}
+
+#[service(interface = "io.ducky.Keystroke")]
+impl<Sock> KeystrokeService
+where
+    Sock::ReadHalf: UnixSocket,
+{
+    #[allow(clippy::unused_async)]
+    async fn send_keys(
+        &mut self,
+        #[zlink(connection)] conn: &mut Connection<Sock>,
+        keys: Vec<String>,
+        pressed: bool,
+        key: Option<String>,
+    ) -> Result<SendKeysResponse, KeystrokeError> {
+        self.authorize(conn, "SendKeys", Permission::Send)?;
+        self.require_config()?;
+
+        // Legacy clients don't say which device the keys come from
+        self.handle_keys(keys, pressed, key, self.redact.resolve(true), false)
+    }
+
+    #[allow(clippy::unused_async)]
+    async fn send_keys_v2(
+        &mut self,
+        #[zlink(connection)] conn: &mut Connection<Sock>,
+        event: KeyEvent,
+    ) -> Result<SendKeysResponse, KeystrokeError> {
+        self.authorize(conn, "SendKeysV2", Permission::Send)?;
+        self.track_sequence(&event);
+        self.device_seen(&event.device);
+        self.require_config()?;
+
+        let redact = self.redact.resolve(event.redact);
+        if event.repeat {
+            self.handle_repeat(event.held, &event.key, redact)
+        } else {
+            self.handle_keys(
+                event.held,
+                event.pressed,
+                Some(event.key),
+                redact,
+                event.strict,
+            )
+        }
+    }
+
+    #[allow(clippy::unused_async)]
+    async fn get_layer(
+        &self,
+        #[zlink(connection)] conn: &mut Connection<Sock>,
+    ) -> Result<LayerResponse, KeystrokeError> {
+        self.authorize(conn, "GetLayer", Permission::Send)?;
+        Ok(self.layer_response())
+    }
+
+    #[allow(clippy::unused_async)]
+    async fn set_layer(
+        &mut self,
+        #[zlink(connection)] conn: &mut Connection<Sock>,
+        layer: String,
+    ) -> Result<LayerResponse, KeystrokeError> {
+        self.authorize(conn, "SetLayer", Permission::Manage)?;
+        {
+            let mut dispatcher = self.relay.dispatcher();
+            if !dispatcher.layers().contains(&layer) {
+                return Err(KeystrokeError::UnknownLayer { layer });
+            }
+            dispatcher.layer_state_mut().switch(&layer);
+        }
+
+        info!("Layer set to '{layer}'");
+
+        Ok(self.layer_response())
+    }
+
+    #[allow(clippy::unused_async)]
+    async fn get_status(
+        &self,
+        #[zlink(connection)] conn: &mut Connection<Sock>,
+    ) -> Result<StatusResponse, KeystrokeError> {
+        self.authorize(conn, "GetStatus", Permission::Send)?;
+        Ok(self.status_response())
+    }
+
+    /// Replace the devices a capture daemon has grabbed, forgetting the
+    /// capture daemon if there are none
+    #[allow(clippy::unused_async)]
+    async fn report_devices(
+        &mut self,
+        #[zlink(connection)] conn: &mut Connection<Sock>,
+        client: String,
+        devices: Vec<CaptureDevice>,
+    ) -> Result<(), KeystrokeError> {
+        self.authorize(conn, "ReportDevices", Permission::Send)?;
+
+        if devices.is_empty() {
+            info!("Capture client '{client}' released its devices");
+            self.clients.remove(&client);
+            return Ok(());
+        }
+
+        for device in &devices {
+            info!(device = %device.id, "Capture client '{client}' grabbed '{}'", device.name);
+        }
+        self.clients.insert(client, (devices, Instant::now()));
+        Ok(())
+    }
+
+    /// Switch dry-run mode, in which commands are logged and recorded
+    /// instead of executed
+    #[allow(clippy::unused_async)]
+    async fn set_dry_run(
+        &mut self,
+        #[zlink(connection)] conn: &mut Connection<Sock>,
+        enabled: bool,
+    ) -> Result<(), KeystrokeError> {
+        self.authorize(conn, "SetDryRun", Permission::Manage)?;
+
+        if self.relay.dry_run.swap(enabled, Ordering::Relaxed) != enabled {
+            if enabled {
+                info!("Dry run enabled, commands are logged but not executed");
+            } else {
+                info!("Dry run disabled, executing commands again");
+            }
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::unused_async)]
+    async fn get_history(
+        &self,
+        #[zlink(connection)] conn: &mut Connection<Sock>,
+    ) -> Result<HistoryResponse, KeystrokeError> {
+        self.authorize(conn, "GetHistory", Permission::Send)?;
+        Ok(HistoryResponse {
+            commands: self.relay.history().iter().cloned().collect(),
+        })
+    }
+
+    /// Report how a command started by a key event is going, failing if it
+    /// failed or is still running `timeout_ms` after it started
+    #[allow(clippy::unused_async)]
+    async fn get_command(
+        &self,
+        #[zlink(connection)] conn: &mut Connection<Sock>,
+        id: u64,
+        timeout_ms: Option<u64>,
+    ) -> Result<CommandStatus, KeystrokeError> {
+        self.authorize(conn, "GetCommand", Permission::Send)?;
+        self.relay
+            .command_status(id, timeout_ms.map(Duration::from_millis))
+    }
+
+    /// Reply with every command triggered from now on, or only with the
+    /// most recent one without `more`
+    #[zlink(more)]
+    async fn monitor(
+        &self,
+        more: bool,
+        #[zlink(connection)] conn: &mut Connection<Sock>,
+    ) -> impl futures_util::Stream<Item = Reply<MonitorEvent>> + Unpin {
+        if let Err(e) = self.authorize(conn, "Monitor", Permission::Send) {
+            // Streaming methods can't return errors, the stream just ends
+            if let Err(e) = conn.send_error(&e, Vec::new()).await {
+                debug!("Failed to deny Monitor: {e}");
+            }
+            return monitor_replies(None, None);
+        }
+
+        if more {
+            monitor_replies(None, Some(self.relay.monitor.subscribe()))
+        } else {
+            let latest = MonitorEvent {
+                command: self.relay.history().back().cloned(),
+            };
+            monitor_replies(Some(latest), None)
+        }
+    }
+}
+
+/// Replies of a `Monitor` call: `latest` as the only reply, or the commands
+/// received from `receiver`
+fn monitor_replies(
+    latest: Option<MonitorEvent>,
+    receiver: Option<broadcast::Receiver<CommandRecord>>,
+) -> BoxStream<'static, Reply<MonitorEvent>> {
+    let latest = latest.map(|event| Reply::new(Some(event)).set_continues(Some(false)));
+    let commands = stream::unfold(receiver, |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(command) => {
+                    let event = MonitorEvent {
+                        command: Some(command),
+                    };
+                    let reply = Reply::new(Some(event)).set_continues(Some(true));
+                    return Some((reply, Some(receiver)));
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    warn!("A Monitor caller fell behind and missed {missed} commands");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    stream::iter(latest).chain(commands).boxed()
+}
@@ -0,0 +1,132 @@
+//! Environment of the user's graphical session.
+//!
+//! Commands started by the service don't inherit the session's
+//! environment, so without `WAYLAND_DISPLAY`, `DBUS_SESSION_BUS_ADDRESS` and
+//! the like, GUI commands and notifications fail. The variables are imported
+//! from the user's systemd manager, which compositors and session startup
+//! scripts export them to, each time a command runs, so a session started
+//! after the service is still found.
+
+use super::config::SessionConfig;
+use super::executor::lookup_uid;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use tracing::debug;
+
+/// Builds the environment of commands from the session and the config
+#[derive(Debug)]
+pub struct Session {
+    import: bool,
+    variables: Vec<String>,
+    overrides: BTreeMap<String, String>,
+}
+
+impl Session {
+    pub fn from_config(config: &SessionConfig) -> Result<Self, String> {
+        for name in config.variables.iter().chain(config.env.keys()) {
+            if name.is_empty() || name.contains(['=', '\0']) {
+                return Err(format!("session variable '{name}' is not a valid name"));
+            }
+        }
+        if config.env.values().any(|value| value.contains('\0')) {
+            return Err("session variables may not contain NUL characters".to_string());
+        }
+
+        Ok(Self {
+            import: config.import,
+            variables: config.variables.clone(),
+            overrides: config.env.clone(),
+        })
+    }
+
+    /// Variables to set for a command run as `user`
+    ///
+    /// If the user's systemd manager can't be reached, `XDG_RUNTIME_DIR` and
+    /// `DBUS_SESSION_BUS_ADDRESS` are derived from the user's UID.
+    pub fn environment(&self, user: &str) -> Vec<(String, String)> {
+        let mut env = BTreeMap::new();
+        if self.import {
+            if let Some(uid) = lookup_uid(user) {
+                env.extend(fallback_environment(uid));
+            }
+            match manager_environment(user) {
+                Ok(manager) => env.extend(manager),
+                Err(e) => debug!("Failed to import the session environment of '{user}': {e}"),
+            }
+            env.retain(|name, _| self.variables.contains(name));
+        }
+        env.extend(self.overrides.clone());
+        env.into_iter().collect()
+    }
+}
+
+/// The runtime directory and session bus systemd sets up for a logged in user
+fn fallback_environment(uid: u32) -> Vec<(String, String)> {
+    let runtime_dir = format!("/run/user/{uid}");
+    let bus = format!("{runtime_dir}/bus");
+    let mut env = Vec::new();
+    if Path::new(&bus).exists() {
+        env.push((
+            "DBUS_SESSION_BUS_ADDRESS".to_string(),
+            format!("unix:path={bus}"),
+        ));
+    }
+    if Path::new(&runtime_dir).is_dir() {
+        env.push(("XDG_RUNTIME_DIR".to_string(), runtime_dir));
+    }
+    env
+}
+
+/// Environment of the systemd manager of `user`
+///
+/// Root reaches the manager of any user, other users only their own.
+fn manager_environment(user: &str) -> Result<Vec<(String, String)>, String> {
+    let mut command = Command::new("systemctl");
+    command.arg("--user");
+    if rustix::process::geteuid().is_root() {
+        command.arg(format!("--machine={user}@.host"));
+    }
+    let output = command
+        .arg("show-environment")
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| format!("failed to run systemctl: {e}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(stderr.lines().collect::<Vec<_>>().join("; "));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(name, value)| (name.to_string(), unquote(value)))
+        .collect())
+}
+
+/// Undo the `$'...'` quoting `systemctl` uses for values that aren't safe
+/// to use in a shell as they are
+fn unquote(value: &str) -> String {
+    let Some(quoted) = value
+        .strip_prefix("$'")
+        .and_then(|value| value.strip_suffix('\''))
+    else {
+        return value.to_string();
+    };
+
+    let mut unquoted = String::with_capacity(quoted.len());
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unquoted.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unquoted.push('\n'),
+            Some('t') => unquoted.push('\t'),
+            Some(c) => unquoted.push(c),
+            None => {}
+        }
+    }
+    unquoted
+}
@@ -0,0 +1,190 @@
+//! Running commands as transient systemd units.
+//!
+//! Each command becomes a service unit of its own in the user's systemd
+//! manager, grouped in `duckycap.slice`. `systemd-run` returns as soon as the
+//! unit is started, so the command is the manager's child rather than the
+//! service's: it shows up in `systemctl --user`, gets the resource limits of
+//! its binding, doesn't keep the service from exiting idle, and is cleaned up
+//! with its cgroup. How the command exits is only logged by the manager. The
+//! manager is found through the `XDG_RUNTIME_DIR` of the command's
+//! [session](super::session) environment, and commands run the way they
+//! would without units if it can't be.
+
+use super::config::{BASE_LAYER, CommandMapping, Config};
+use super::executor::{Executor, Job, Ran, login_shell, run};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::warn;
+
+/// Slice the units of commands are grouped in
+const SLICE: &str = "duckycap";
+
+/// Properties of the units commands run as
+#[derive(Debug)]
+pub struct Units {
+    /// Properties of every unit
+    properties: BTreeMap<String, String>,
+    /// Properties of the units of each binding, by binding ID
+    bindings: HashMap<String, BTreeMap<String, String>>,
+}
+
+impl Units {
+    /// Unit settings of a config, `None` if units aren't enabled
+    ///
+    /// The properties of mappings are checked either way, so a config doesn't
+    /// break when units are enabled later.
+    pub fn from_config(config: &Config) -> Result<Option<Self>, String> {
+        let mut mappings: Vec<(&str, &[CommandMapping])> = vec![(BASE_LAYER, &config.commands)];
+        for (name, layer) in &config.layers {
+            mappings.push((name, &layer.commands));
+        }
+
+        let mut bindings = HashMap::new();
+        for (layer, commands) in mappings {
+            for (index, mapping) in commands.iter().enumerate() {
+                if mapping.unit.is_empty() {
+                    continue;
+                }
+                let id = mapping.id(layer, index);
+                check_properties(&mapping.unit).map_err(|e| format!("binding '{id}': {e}"))?;
+                bindings.insert(id, mapping.unit.clone());
+            }
+        }
+        check_properties(&config.units.properties)?;
+
+        if !config.units.enabled {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            properties: config.units.properties.clone(),
+            bindings,
+        }))
+    }
+
+    /// Properties of the unit of a command of `binding`
+    fn properties(&self, binding: &str) -> BTreeMap<&str, &str> {
+        let mut properties: BTreeMap<&str, &str> = self
+            .properties
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect();
+        if let Some(overrides) = self.bindings.get(binding) {
+            properties.extend(
+                overrides
+                    .iter()
+                    .map(|(name, value)| (name.as_str(), value.as_str())),
+            );
+        }
+        properties
+    }
+}
+
+/// Check that unit properties can be passed to `systemd-run`
+///
+/// Whether systemd knows them is only found out when a command runs.
+fn check_properties(properties: &BTreeMap<String, String>) -> Result<(), String> {
+    for name in properties.keys() {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!("unit property '{name}' is not a valid name"));
+        }
+    }
+    Ok(())
+}
+
+/// Runs commands as transient units with `systemd-run`
+pub struct Transient {
+    /// Whether commands run as another user, whose manager root reaches with
+    /// `--machine`
+    other_user: bool,
+    units: Units,
+    /// Number of units started, to name them
+    started: AtomicU64,
+}
+
+impl Transient {
+    /// Run commands as units, `None` if `systemd-run` isn't available
+    pub fn new(other_user: bool, units: Units) -> Option<Self> {
+        let available = Command::new("systemd-run")
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success());
+        if !available {
+            warn!("systemd-run isn't available, running commands without units");
+            return None;
+        }
+
+        Some(Self {
+            other_user,
+            units,
+            started: AtomicU64::new(0),
+        })
+    }
+
+    /// Name of the next unit of a command of `binding`
+    fn unit_name(&self, binding: &str) -> String {
+        let binding: String = binding
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '.' | ':' => c,
+                _ => '_',
+            })
+            .collect();
+        let count = self.started.fetch_add(1, Ordering::Relaxed) + 1;
+        format!("duckycap-{binding}-{}-{count}", std::process::id())
+    }
+}
+
+impl Executor for Transient {
+    /// Start a job's command as a unit
+    ///
+    /// Returns once the unit is started, so the command is only launched.
+    fn execute(&self, job: &Job) -> Result<Ran, String> {
+        let shell = login_shell(job.cmd)?;
+
+        // systemd-run finds the user's manager in the runtime directory
+        let reachable = job
+            .env
+            .iter()
+            .find(|(name, _)| name == "XDG_RUNTIME_DIR")
+            .is_some_and(|(_, dir)| Path::new(dir).join("systemd/private").exists());
+        if !reachable {
+            warn!(
+                binding = %job.binding,
+                "The systemd manager of user '{}' isn't reachable, running '{}' without a unit",
+                job.user,
+                job.cmd
+            );
+            return run(self.other_user.then_some(job.user), shell, job.env).map(Ran::Exited);
+        }
+
+        let mut args: Vec<String> = ["systemd-run", "--user", "--quiet", "--collect"]
+            .into_iter()
+            .map(ToString::to_string)
+            .collect();
+        if self.other_user {
+            args.push(format!("--machine={}@.host", job.user));
+        }
+        args.push(format!("--slice={SLICE}"));
+        args.push(format!("--unit={}", self.unit_name(job.binding)));
+        args.push(format!("--description=ducky-relay binding {}", job.binding));
+        for (name, value) in self.units.properties(job.binding) {
+            args.push(format!("--property={name}={value}"));
+        }
+        // Units get the manager's environment, not the one of systemd-run
+        for (name, value) in job.env {
+            args.push(format!("--setenv={name}={value}"));
+        }
+        args.push("--".to_string());
+        args.extend(shell);
+
+        match run(None, args, job.env)? {
+            Some(0) => Ok(Ran::Launched),
+            Some(code) => Err(format!("systemd-run failed with exit code {code}")),
+            None => Err("systemd-run was killed by a signal".to_string()),
+        }
+    }
+}
@@ -0,0 +1,332 @@
+//! Integration tests of the keystroke service.
+//!
+//! Each test starts the service on a socket in its own temporary directory,
+//! with a `Recorder` in place of `runuser`, and drives it with
+//! `KeystrokeProxy` like the capture daemon does.
+
+use ducky_relay::service::{Recorder, ServerConfig, run_server};
+use ducky_relay::{CommandOutcome, KeyEvent, KeystrokeError, KeystrokeProxy};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use zlink::{Connection, unix};
+
+const CONFIG: &str = r#"
+debounce_ms = 0
+
+[[commands]]
+keys = "a"
+cmd = "echo a"
+
+[[commands]]
+name = "fails"
+keys = "b"
+cmd = "exit 3"
+
+[layers.obs]
+[[layers.obs.commands]]
+keys = "c"
+cmd = "obs-cmd replay save"
+"#;
+
+/// The service running on a temporary socket
+struct TestService {
+    dir: PathBuf,
+    recorder: Recorder,
+}
+
+impl TestService {
+    /// Start the service with `config`, running commands as the user running
+    /// the tests, until the test process exits
+    async fn start(name: &str, config: &str, recorder: Recorder) -> Self {
+        let dir =
+            std::env::temp_dir().join(format!("ducky-relay-test-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let path = dir.join("config.toml");
+        std::fs::write(&path, format!("user = \"{}\"\n{config}", current_user())).unwrap();
+
+        let mut server = ServerConfig::load(path);
+        server.socket = dir.join("socket");
+        server.executor = Arc::new(recorder.clone());
+        // The server future isn't `Send`, so it gets a runtime of its own
+        std::thread::spawn(|| {
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(run_server(server));
+        });
+
+        let service = Self { dir, recorder };
+        for _ in 0..100 {
+            if service.socket().exists() {
+                return service;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the service didn't bind {}", service.socket().display());
+    }
+
+    fn socket(&self) -> PathBuf {
+        self.dir.join("socket")
+    }
+
+    async fn connect(&self) -> Connection<unix::Stream> {
+        unix::connect(self.socket()).await.unwrap()
+    }
+}
+
+impl Drop for TestService {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Name of the user running the tests, the only one a service not running
+/// as root can run commands as
+fn current_user() -> String {
+    let output = std::process::Command::new("id")
+        .arg("-un")
+        .output()
+        .unwrap();
+    String::from_utf8(output.stdout).unwrap().trim().to_string()
+}
+
+/// Wait until the commands started so far have finished
+async fn finish(conn: &mut Connection<unix::Stream>) {
+    for _ in 0..100 {
+        if conn.get_status().await.unwrap().unwrap().running_commands == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("commands are still running");
+}
+
+/// A key event from a device
+fn event(held: &[&str], key: &str, pressed: bool) -> KeyEvent {
+    KeyEvent {
+        key: key.to_string(),
+        pressed,
+        repeat: false,
+        held: held.iter().map(ToString::to_string).collect(),
+        timestamp_usec: 1_760_000_000_000_000,
+        sequence: 0,
+        device: "test".to_string(),
+        redact: false,
+        strict: false,
+    }
+}
+
+#[tokio::test]
+async fn press_runs_the_bound_command() {
+    let service = TestService::start("press", CONFIG, Recorder::default()).await;
+    let mut conn = service.connect().await;
+
+    let response = conn
+        .send_keys_v2(&event(&["a"], "a", true))
+        .await
+        .unwrap()
+        .unwrap();
+    conn.send_keys_v2(&event(&["a"], "a", false))
+        .await
+        .unwrap()
+        .unwrap();
+    finish(&mut conn).await;
+    let [id] = response.commands[..] else {
+        panic!("unexpected commands {:?}", response.commands);
+    };
+    let command = conn.get_command(id, None).await.unwrap().unwrap();
+    let unknown = conn.get_command(id + 1, None).await.unwrap().unwrap_err();
+
+    assert!(response.pressed);
+    assert_eq!(response.keys, vec!["a"]);
+    assert_eq!(
+        service.recorder.commands(),
+        vec![(current_user(), "echo a".to_string())]
+    );
+    assert_eq!(command.outcome, CommandOutcome::Succeeded);
+    assert_eq!(unknown, KeystrokeError::UnknownCommand { id: id + 1 });
+}
+
+#[tokio::test]
+async fn failed_command_is_reported_and_recorded() {
+    let recorder = Recorder::default().fail("exit 3", 3);
+    let service = TestService::start("failed", CONFIG, recorder).await;
+    let mut conn = service.connect().await;
+
+    let response = conn
+        .send_keys_v2(&event(&["b"], "b", true))
+        .await
+        .unwrap()
+        .unwrap();
+    finish(&mut conn).await;
+
+    let history = conn.get_history().await.unwrap().unwrap();
+    let status = conn.get_status().await.unwrap().unwrap();
+    let error = conn
+        .get_command(response.commands[0], Some(1000))
+        .await
+        .unwrap()
+        .unwrap_err();
+
+    let outcomes: Vec<_> = history.commands.iter().map(|c| c.outcome).collect();
+    assert_eq!(
+        outcomes,
+        vec![CommandOutcome::Started, CommandOutcome::Failed]
+    );
+    assert_eq!(history.commands[1].binding, "fails");
+    let message = history.commands[1].message.as_deref().unwrap();
+    assert!(message.contains("exit code 3"), "{message}");
+    assert!(status.last_error.is_some());
+    let KeystrokeError::CommandFailed { binding, message } = error else {
+        panic!("unexpected error {error:?}");
+    };
+    assert_eq!(binding, "fails");
+    assert!(message.contains("exit code 3"), "{message}");
+}
+
+#[tokio::test]
+async fn launched_command_is_not_reported_as_succeeded() {
+    let recorder = Recorder::default().launch("echo a");
+    let service = TestService::start("launched", CONFIG, recorder).await;
+    let mut conn = service.connect().await;
+
+    let response = conn
+        .send_keys_v2(&event(&["a"], "a", true))
+        .await
+        .unwrap()
+        .unwrap();
+    finish(&mut conn).await;
+
+    let history = conn.get_history().await.unwrap().unwrap();
+    let command = conn
+        .get_command(response.commands[0], None)
+        .await
+        .unwrap()
+        .unwrap();
+
+    let outcomes: Vec<_> = history.commands.iter().map(|c| c.outcome).collect();
+    assert_eq!(
+        outcomes,
+        vec![CommandOutcome::Started, CommandOutcome::Launched]
+    );
+    assert_eq!(command.outcome, CommandOutcome::Launched);
+}
+
+#[tokio::test]
+async fn strict_press_without_binding_fails() {
+    let service = TestService::start("strict", CONFIG, Recorder::default()).await;
+    let mut conn = service.connect().await;
+
+    let mut strict = event(&["z"], "z", true);
+    strict.strict = true;
+    let error = conn.send_keys_v2(&strict).await.unwrap().unwrap_err();
+
+    assert_eq!(
+        error,
+        KeystrokeError::UnknownBinding {
+            keys: vec!["z".to_string()]
+        }
+    );
+    assert!(service.recorder.commands().is_empty());
+}
+
+#[tokio::test]
+async fn empty_keys_are_invalid() {
+    let service = TestService::start("invalid", CONFIG, Recorder::default()).await;
+    let mut conn = service.connect().await;
+
+    let error = conn.send_keys(&[], true, None).await.unwrap().unwrap_err();
+
+    assert!(matches!(error, KeystrokeError::InvalidKey { .. }));
+}
+
+#[tokio::test]
+async fn dry_run_records_commands_without_running_them() {
+    let service = TestService::start("dry-run", CONFIG, Recorder::default()).await;
+    let mut conn = service.connect().await;
+
+    conn.set_dry_run(true).await.unwrap().unwrap();
+    conn.send_keys_v2(&event(&["a"], "a", true))
+        .await
+        .unwrap()
+        .unwrap();
+
+    let history = conn.get_history().await.unwrap().unwrap();
+    let status = conn.get_status().await.unwrap().unwrap();
+
+    assert!(service.recorder.commands().is_empty());
+    assert_eq!(history.commands.len(), 1);
+    assert_eq!(history.commands[0].command, "echo a");
+    assert_eq!(history.commands[0].outcome, CommandOutcome::DryRun);
+    assert_eq!(history.commands[0].keys, Some(vec!["a".to_string()]));
+    assert!(status.dry_run);
+}
+
+#[tokio::test]
+async fn layers_can_be_switched() {
+    let service = TestService::start("layers", CONFIG, Recorder::default()).await;
+    let mut conn = service.connect().await;
+
+    let layer = conn.set_layer("obs").await.unwrap().unwrap();
+    conn.send_keys_v2(&event(&["c"], "c", true))
+        .await
+        .unwrap()
+        .unwrap();
+    finish(&mut conn).await;
+    let error = conn.set_layer("nope").await.unwrap().unwrap_err();
+
+    assert_eq!(layer.layer, "obs");
+    assert_eq!(layer.layers, vec!["base", "obs"]);
+    assert_eq!(
+        service.recorder.commands(),
+        vec![(current_user(), "obs-cmd replay save".to_string())]
+    );
+    assert_eq!(
+        error,
+        KeystrokeError::UnknownLayer {
+            layer: "nope".to_string()
+        }
+    );
+    assert_eq!(conn.get_layer().await.unwrap().unwrap().layer, "obs");
+}
+
+#[tokio::test]
+async fn broken_config_rejects_key_events() {
+    let service = TestService::start("broken", "user = [", Recorder::default()).await;
+    let mut conn = service.connect().await;
+
+    let error = conn
+        .send_keys_v2(&event(&["a"], "a", true))
+        .await
+        .unwrap()
+        .unwrap_err();
+    let status = conn.get_status().await.unwrap().unwrap();
+
+    assert!(matches!(error, KeystrokeError::ConfigNotLoaded { .. }));
+    assert_eq!(status.bindings, 0);
+    assert!(status.last_error.is_some());
+}
+
+#[tokio::test]
+async fn commands_get_the_configured_session_environment() {
+    let config = format!(
+        "{CONFIG}\n[session]\nimport = false\n[session.env]\nWAYLAND_DISPLAY = \"wayland-1\"\n"
+    );
+    let service = TestService::start("session", &config, Recorder::default()).await;
+    let mut conn = service.connect().await;
+
+    conn.send_keys_v2(&event(&["a"], "a", true))
+        .await
+        .unwrap()
+        .unwrap();
+    finish(&mut conn).await;
+
+    assert_eq!(
+        service.recorder.environments(),
+        vec![vec![(
+            "WAYLAND_DISPLAY".to_string(),
+            "wayland-1".to_string()
+        )]]
+    );
+}